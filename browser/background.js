@@ -4,14 +4,29 @@ const Desktopd = {
     console.log("initializing desktopd")
 
     const STORAGE_KEY = "desktopd-browser-id"
+    const TOKEN_KEY = "desktopd-token"
     const chan = new MessageChannel()
     const url = 'ws://localhost:8080'
+    const TAG_TIMEOUT = 1000
+    // while unpaired, check back this often, well within the 60s `dsktpd pair` keeps pairing open
+    const PAIRING_POLL = 20000
+    // must be one the daemon supports, see PROTOCOL_VERSION in native/src/message.rs
+    const PROTOCOL_VERSION = 1
+    const CAPABILITIES = ["tag_window", "tab_commands"]
     var ws;
+    // set when the daemon speaks another protocol version, retrying won't help then
+    var incompatible = false;
+    // set when the daemon refused our token, only pairing again will help then
+    var unpaired = false;
 
     function getId() {
       return window.localStorage.getItem(STORAGE_KEY)
     }
 
+    function getToken() {
+      return window.localStorage.getItem(TOKEN_KEY)
+    }
+
     function listTabs() {
       return browser.tabs.query({})
     }
@@ -20,6 +35,16 @@ const Desktopd = {
       return browser.tabs.query(props).then((tabs) => tabs[0])
     }
 
+    // there is no way to read the preface back, but it is what the window title starts with,
+    // before the title of the active tab
+    function getTitlePreface(windowId) {
+      return browser.windows.get(windowId, { populate: true }).then((win) => {
+        const active = win.tabs.find((tab) => tab.active)
+        const at = active && win.title ? win.title.indexOf(active.title) : -1
+        return at > 0 ? win.title.slice(0, at) : ""
+      })
+    }
+
     function getExtensionInfo() {
       return browser.management.getSelf()
     }
@@ -35,11 +60,123 @@ const Desktopd = {
       }
     }
 
+    function handleBrowserRequest(cmd) {
+      switch (cmd.browser_request) {
+        case 'tag_window':
+          // briefly prefix the window title so desktopd can find the matching sway window, then
+          // put back whatever preface the user or another extension had set
+          getTitlePreface(cmd.windowId).then((preface) =>
+            browser.windows.update(cmd.windowId, {
+              titlePreface: cmd.nonce + " " + preface
+            }).then(() =>
+              setTimeout(() => {
+                browser.windows.update(cmd.windowId, {
+                  titlePreface: preface
+                })
+              }, TAG_TIMEOUT)
+            )
+          ).catch((e) =>
+            console.log("could not tag window", cmd.windowId, e)
+          )
+          break
+        case 'close_tab':
+        case 'reload_tab':
+        case 'duplicate_tab':
+        case 'pin_tab':
+        case 'mute_tab':
+        case 'move_tab_to_window':
+        case 'open_url':
+          acknowledge(cmd, runTabCommand(cmd))
+      }
+    }
+
+    function tabRef(tab) {
+      return {
+        tabId: tab.id,
+        windowId: tab.windowId
+      }
+    }
+
+    // pin_tab and mute_tab toggle unless told which way to go
+    function runTabCommand(cmd) {
+      switch (cmd.browser_request) {
+        case 'close_tab':
+          return browser.tabs.remove(cmd.tabId).then(() => null)
+        case 'reload_tab':
+          return browser.tabs.reload(cmd.tabId).then(() => null)
+        case 'duplicate_tab':
+          return browser.tabs.duplicate(cmd.tabId).then(tabRef)
+        case 'pin_tab':
+          return browser.tabs.get(cmd.tabId).then((tab) =>
+            browser.tabs.update(cmd.tabId, {
+              pinned: cmd.pinned == null ? !tab.pinned : cmd.pinned
+            })
+          ).then(() => null)
+        case 'mute_tab':
+          return browser.tabs.get(cmd.tabId).then((tab) =>
+            browser.tabs.update(cmd.tabId, {
+              muted: cmd.muted == null ? !tab.mutedInfo.muted : cmd.muted
+            })
+          ).then(() => null)
+        case 'move_tab_to_window':
+          return browser.tabs.move(cmd.tabId, {
+            windowId: cmd.toWindowId,
+            index: cmd.index == null ? -1 : cmd.index
+          }).then(() => null)
+        case 'open_url':
+          return browser.tabs.create({
+            url: cmd.url,
+            windowId: cmd.windowId
+          }).then(tabRef)
+      }
+    }
+
+    // tell desktopd how a request went, so it can answer whoever asked for it
+    function acknowledge(cmd, result) {
+      result.then((result) =>
+        chan.port2.postMessage(JSON.stringify({
+          msg_type: "response",
+          request_id: cmd.request_id,
+          result: result
+        }))
+      ).catch((e) => {
+        console.log("could not run", cmd.browser_request, e)
+        chan.port2.postMessage(JSON.stringify({
+          msg_type: "error",
+          request_id: cmd.request_id,
+          kind: "command_failed",
+          message: e.toString()
+        }))
+      })
+    }
+
     function handleCommand(cmd) {
       switch (cmd.msg_type) {
         case 'cli_request':
           handleCliRequest(cmd)
           break
+        case 'browser_request':
+          handleBrowserRequest(cmd)
+          break
+        case 'welcome':
+          console.log("connected to desktopd, protocol version", cmd.version, "capabilities", cmd.capabilities)
+          break
+        case 'error':
+          console.error("desktopd:", cmd.kind, cmd.message)
+          if (cmd.kind == 'incompatible_version') {
+            incompatible = true
+          }
+          if (cmd.kind == 'unauthorized') {
+            unpaired = true
+            window.localStorage.removeItem(TOKEN_KEY)
+          }
+          break
+        case 'paired':
+          // sent once, after `dsktpd pair`, the token is needed to connect from now on
+          console.log("paired with desktopd")
+          unpaired = false
+          window.localStorage.setItem(TOKEN_KEY, cmd.token)
+          break
         default:
           console.log('unhandled command', cmd)
       }
@@ -56,11 +193,30 @@ const Desktopd = {
       return {
         msg_type: "connect",
         application: "browser",
-        id: getId()
+        id: getId(),
+        token: getToken(),
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES
+      }
+    }
+
+    // in a second, or once the user might have paired again if the token was refused
+    function reconnect() {
+      if (unpaired) {
+        console.log("not paired with desktopd, run `dsktpd pair`")
+        setTimeout(connect, PAIRING_POLL)
+      } else {
+        console.log("reconnect will be attempted in 1 second")
+        setTimeout(connect, 1000)
       }
     }
 
     function connect() {
+      if (incompatible) {
+        console.log("not reconnecting, desktopd speaks another protocol version")
+        return
+      }
+
       console.log("connecting to", url)
 
       if (ws != null && ws != undefined) {
@@ -91,20 +247,15 @@ const Desktopd = {
       }
 
       ws.onclose = (e) => {
-        console.log('Socket is closed. Reconnect will be attempted in 1 second.', e.reason)
+        console.log('Socket is closed.', e.reason)
         ws.close()
-        setTimeout(() => {
-          connect()
-        }, 1000)
+        reconnect()
       }
 
       ws.onerror = (err) => {
         console.error('Socket encountered error: ', err, 'Closing socket')
         ws.close()
-        // attempting to reconnect in a second
-        setTimeout(() => {
-          connect()
-        }, 1000)
+        reconnect()
       }
 
       // messages from channel are forwarded to desktopd
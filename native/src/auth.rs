@@ -0,0 +1,69 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long `dsktpd pair` lets a browser without a token connect.
+pub const PAIRING_WINDOW: Duration = Duration::from_secs(60);
+
+/// The header the HTTP API expects the token in.
+pub const TOKEN_HEADER: &str = "X-Desktopd-Token";
+
+/// `$XDG_RUNTIME_DIR/desktopd.token`, the secret peers have to present when connecting.
+pub fn token_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("desktopd.token"))
+}
+
+/// Read the token, creating it (readable by us only) if this is the first daemon since login.
+pub fn load_or_create() -> io::Result<String> {
+    let path = token_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+    {
+        Ok(mut file) => {
+            let token = generate()?;
+            file.write_all(token.as_bytes())?;
+            Ok(token)
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => read_token(),
+        Err(err) => Err(err),
+    }
+}
+
+/// The token of the running daemon, for clients.
+pub fn read_token() -> io::Result<String> {
+    let path = token_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+    Ok(fs::read_to_string(path)?.trim().to_owned())
+}
+
+/// 32 random bytes, hex encoded.
+pub fn generate() -> io::Result<String> {
+    let mut bytes = [0u8; 32];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Whether the `Origin` of a websocket connection is a browser extension rather than a web page.
+pub fn is_extension_origin(origin: &str) -> bool {
+    origin.starts_with("moz-extension://") || origin.starts_with("chrome-extension://")
+}
+
+/// Compare tokens without giving away how much of a guess was right.
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
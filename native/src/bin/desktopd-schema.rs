@@ -0,0 +1,50 @@
+use desktopd::schema;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// Write the protocol schema and the OpenAPI description to `schema/`, or with `--check`, fail if
+/// the files there no longer match the code, e.g. because a message changed and nobody ran
+/// `cargo run --bin desktopd-schema` afterwards.
+fn main() {
+    let check = match std::env::args().nth(1).as_deref() {
+        Some("--check") => true,
+        None => false,
+        Some(arg) => {
+            eprintln!("Unknown argument {}, usage: desktopd-schema [--check]", arg);
+            process::exit(2)
+        }
+    };
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("schema");
+    let files = vec![
+        (
+            "protocol.json",
+            serde_json::to_value(schema::protocol()).unwrap(),
+        ),
+        ("openapi.json", schema::openapi()),
+    ];
+
+    let mut stale = false;
+    for (name, schema) in files {
+        let path = dir.join(name);
+        let mut json = serde_json::to_string_pretty(&schema).unwrap();
+        json.push('\n');
+
+        if check {
+            if fs::read_to_string(&path).ok().as_deref() != Some(&json[..]) {
+                eprintln!("{} is out of date", path.display());
+                stale = true;
+            }
+        } else {
+            fs::create_dir_all(&dir).expect("Could not create schema directory");
+            fs::write(&path, json).expect("Could not write schema");
+            println!("Wrote {}", path.display());
+        }
+    }
+
+    if stale {
+        eprintln!("Run `cargo run --bin desktopd-schema` and commit the result.");
+        process::exit(1)
+    }
+}
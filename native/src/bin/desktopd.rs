@@ -1,20 +1,52 @@
 #![feature(async_closure)]
 use async_std::task;
+use desktopd::auth;
+use desktopd::frecency::Frecency;
 use desktopd::http;
+use desktopd::socket;
 use desktopd::state::*;
 use desktopd::sway;
+use desktopd::tmux;
 use desktopd::websocket;
 use futures::channel::mpsc::unbounded;
-use log::info;
+use log::{error, info};
 use std::io;
 use std::sync::Mutex;
+use std::time::Duration;
+
+const FRECENCY_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[async_std::main]
 async fn main() -> io::Result<()> {
     let _ = env_logger::try_init();
 
     let (sway_tx, sway_rx) = unbounded();
-    let state = GlobalState::new(Mutex::new(State::new()));
+    let frecency = match Frecency::default_path().map(Frecency::load) {
+        Some(Ok(frecency)) => frecency,
+        Some(Err(err)) => {
+            error!("Could not load frecency scores: {}", err);
+            Frecency::new()
+        }
+        None => Frecency::new(),
+    };
+    let token = auth::load_or_create().unwrap_or_else(|err| {
+        error!(
+            "Could not store token, only paired browsers can connect: {}",
+            err
+        );
+        auth::generate().expect("Could not generate token")
+    });
+    let mut state = State::with_frecency(frecency);
+    state.set_token(token);
+    let state = GlobalState::new(Mutex::new(state));
+
+    let save_state = state.clone();
+    task::spawn(async move {
+        loop {
+            task::sleep(FRECENCY_SAVE_INTERVAL).await;
+            save_frecency(&save_state);
+        }
+    });
 
     let ws_state = state.clone();
     let ws_tx_handle = sway_tx.clone();
@@ -25,6 +57,15 @@ async fn main() -> io::Result<()> {
             .expect("Websocket server failed");
     });
 
+    let socket_state = state.clone();
+    let socket_tx_handle = sway_tx.clone();
+    task::spawn(async {
+        info!("unix socket server starting");
+        if let Err(err) = socket::run(socket_state, socket_tx_handle).await {
+            error!("Unix socket server failed: {}", err);
+        }
+    });
+
     let http_state = state.clone();
     let http_tx_handle = sway_tx.clone();
     task::spawn(async {
@@ -34,5 +75,27 @@ async fn main() -> io::Result<()> {
             .expect("Http server failed");
     });
 
-    sway::connection::run(state, sway_tx, sway_rx).await
+    let tmux_state = state.clone();
+    task::spawn(async {
+        info!("tmux client starting");
+        tmux::connection::run(tmux_state).await
+    });
+
+    let result = sway::connection::run(state.clone(), sway_tx, sway_rx).await;
+
+    save_frecency(&state);
+    result
+}
+
+/// Serialize the scores under the lock, but write them without holding it.
+fn save_frecency(state: &GlobalState) {
+    let unsaved = state.lock().unwrap().unsaved_frecency();
+    let result = match unsaved {
+        Ok(Some(unsaved)) => unsaved.write(),
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        error!("Could not save frecency scores: {}", err);
+    }
 }
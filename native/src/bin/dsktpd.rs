@@ -1,17 +1,37 @@
-use futures::{SinkExt, StreamExt};
+use futures::{future, sink, AsyncBufReadExt, AsyncWriteExt, Sink, SinkExt, Stream, StreamExt};
 
+use async_std::io::BufReader;
+use async_std::os::unix::net::UnixStream;
 use async_std::task;
 use async_tungstenite::async_std::connect_async;
 use async_tungstenite::tungstenite::protocol::Message;
+use desktopd::auth;
 use desktopd::browser::*;
 use desktopd::message::*;
+use desktopd::sway::types::{SwayOutput, SwayWorkspace};
 use notify_rust::Notification;
+use serde::de::DeserializeOwned;
 use skim::prelude::*;
+use std::pin::Pin;
+use std::time::Duration;
 use tabular::{Row, Table};
 use url::Url;
 
 const WIDTH: usize = 80;
 
+/// Keys that act on the selected window or tab rather than focusing it, see `window_action` and
+/// `tab_action`.
+const ACTIONS: &str = "alt-c,alt-w,alt-o,alt-f,alt-m,alt-s,alt-r,alt-d,alt-p,alt-u";
+
+const HEADER: &str = "windows: alt-c close  alt-w move to workspace  alt-o move to output  \
+                      alt-f floating  alt-m fullscreen  alt-s scratchpad\n\
+                      tabs: alt-c close  alt-r reload  alt-d duplicate  alt-p pin  alt-u mute";
+
+/// Every connection sends a single request, so they can all use the same id.
+const REQUEST_ID: u64 = 1;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct Wrapper {
     client: DesktopdClient,
     line: String,
@@ -23,16 +43,52 @@ impl SkimItem for Wrapper {
     }
 }
 
-type SinkHole = futures::stream::SplitSink<
-    async_tungstenite::WebSocketStream<async_std::net::TcpStream>,
-    async_tungstenite::tungstenite::Message,
->;
+type SinkHole = Pin<Box<dyn Sink<DesktopdMessage, Error = anyhow::Error>>>;
 
-async fn run(tx_item: SkimItemSender) -> SinkHole {
-    let width = std::env::var("DSKTPD_CLIENT_WIDTH")
-        .map(|w| usize::from_str_radix(&w, 10).unwrap_or(WIDTH))
-        .unwrap_or(WIDTH);
+type Source = Pin<Box<dyn Stream<Item = DesktopdMessage>>>;
+
+/// Connect to the daemon, through its unix socket if it has one, or its websocket otherwise.
+async fn connect() -> (SinkHole, Source) {
+    let (mut write, mut read) = match connect_socket().await {
+        Some(connection) => connection,
+        None => connect_websocket().await,
+    };
+
+    // the unix socket doesn't need the token, the websocket does
+    let init = DesktopdMessage::connect(ConnectionType::Cli, auth::read_token().ok(), &[]);
+    write.send(init).await.expect("Could not send init message");
+
+    // the daemon welcomes us first, or tells us why it won't
+    let refusal = match read.next().await {
+        Some(DesktopdMessage::Welcome { .. }) => return (write, read),
+        Some(DesktopdMessage::Error { message, .. }) => message,
+        Some(_) => "The daemon is too old to say which protocol it speaks".to_owned(),
+        None => "The daemon hung up".to_owned(),
+    };
+    report(Err(refusal));
+    std::process::exit(1)
+}
+
+async fn connect_socket() -> Option<(SinkHole, Source)> {
+    let stream = UnixStream::connect(desktopd::socket::default_path()?)
+        .await
+        .ok()?;
+
+    let read = BufReader::new(stream.clone()).lines().filter_map(|line| {
+        future::ready(line.ok().and_then(|line| serde_json::from_str(&line).ok()))
+    });
+
+    let write = sink::unfold(stream, |mut stream, msg: DesktopdMessage| async move {
+        let mut line = serde_json::to_string(&msg)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes()).await?;
+        Ok::<_, anyhow::Error>(stream)
+    });
 
+    Some((Box::pin(write), Box::pin(read)))
+}
+
+async fn connect_websocket() -> (SinkHole, Source) {
     let (ws_stream, _) = connect_async("ws://127.0.0.1:8080")
         .await
         .unwrap_or_else(|e| {
@@ -44,23 +100,219 @@ async fn run(tx_item: SkimItemSender) -> SinkHole {
             panic!("Fatal error: {}", e)
         });
 
-    let init = DesktopdMessage::Connect(ConnectionType::Cli);
-    let msg = Message::Text(serde_json::to_string(&init).unwrap());
+    let (write, read) = ws_stream.split();
+
+    let write = write
+        .sink_map_err(anyhow::Error::from)
+        .with(|msg: DesktopdMessage| {
+            future::ready(
+                serde_json::to_string(&msg)
+                    .map(Message::Text)
+                    .map_err(anyhow::Error::from),
+            )
+        });
+
+    let read = read.filter_map(|msg| {
+        future::ready(
+            msg.ok()
+                .and_then(|msg| serde_json::from_str(msg.to_text().ok()?).ok()),
+        )
+    });
+
+    (Box::pin(write), Box::pin(read))
+}
+
+/// Send a request and wait for the daemon to tell whether it worked out.
+async fn request(
+    write: &mut SinkHole,
+    read: &mut Source,
+    request: CliRequest,
+) -> Result<serde_json::Value, String> {
+    let command = DesktopdMessage::CliRequest {
+        request,
+        request_id: Some(REQUEST_ID),
+        origin: None,
+    };
+    write.send(command).await.map_err(|err| err.to_string())?;
+
+    let reply = async {
+        while let Some(msg) = read.next().await {
+            match msg {
+                DesktopdMessage::Response {
+                    request_id: Some(REQUEST_ID),
+                    result,
+                } => return Ok(result),
+                DesktopdMessage::Error {
+                    request_id: Some(REQUEST_ID),
+                    message,
+                    ..
+                } => return Err(message),
+                _ => (),
+            }
+        }
+        Err("The daemon hung up".to_owned())
+    };
+
+    async_std::future::timeout(REPLY_TIMEOUT, reply)
+        .await
+        .unwrap_or_else(|_| Err("The daemon did not reply in time".to_owned()))
+}
+
+fn report(result: Result<serde_json::Value, String>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            Notification::new()
+                .summary("desktopd")
+                .body(&format!("Error: {}", err))
+                .show()
+                .expect("Could not show notification");
+            false
+        }
+    }
+}
 
-    let (mut write, mut read) = ws_stream.split();
+/// Send a single request without starting the picker, e.g. `dsktpd previous` to bind to a key.
+async fn send_request(req: CliRequest) -> bool {
+    let (mut write, mut read) = connect().await;
+    report(request(&mut write, &mut read, req).await)
+}
+
+/// Subscribe to the given topics (all of them if none are given) and print every event as a line
+/// of JSON, e.g. `dsktpd watch focus` to feed a status bar.
+async fn watch(topics: Vec<String>) {
+    let topics = if topics.is_empty() {
+        Topic::ALL.to_vec()
+    } else {
+        serde_json::from_value::<Vec<Topic>>(serde_json::json!(topics)).unwrap_or_else(|err| {
+            eprintln!("Error: {}", err);
+            std::process::exit(2)
+        })
+    };
 
-    write.send(msg).await.expect("Could not send init message");
+    let (mut write, mut read) = connect().await;
+    write
+        .send(DesktopdMessage::Subscribe { topics })
+        .await
+        .expect("Could not subscribe");
 
-    let response = read.next().await.expect("No response").expect("Error");
+    while let Some(msg) = read.next().await {
+        match msg {
+            // sent to every command line client on connect, it isn't an event
+            DesktopdMessage::ClientList { .. } => (),
+            msg => println!("{}", serde_json::to_string(&msg).unwrap()),
+        }
+    }
+}
 
-    let msg: DesktopdMessage = response
-        .to_text()
-        .map(|txt| serde_json::from_str(txt))
-        .expect("Could not parse")
-        .expect("Could not parse");
+/// The request for a window action key, asking which workspace or output to move the window to
+/// if need be. `None` if the user changed their mind.
+async fn window_action(
+    key: &Key,
+    id: usize,
+    write: &mut SinkHole,
+    read: &mut Source,
+) -> Option<CliRequest> {
+    use CliRequest as Req;
+    match key {
+        Key::Alt('c') => Some(Req::CloseWindow { id }),
+        Key::Alt('f') => Some(Req::ToggleFloating { id }),
+        Key::Alt('m') => Some(Req::ToggleFullscreen { id }),
+        Key::Alt('s') => Some(Req::SendToScratchpad { id }),
+        Key::Alt('w') => {
+            let workspaces: Vec<SwayWorkspace> = list(write, read, Req::ListWorkspaces).await?;
+            let names = workspaces.into_iter().map(|ws| ws.name).collect();
+            let workspace = pick("workspace> ", names)?;
+            Some(Req::MoveWindowToWorkspace { id, workspace })
+        }
+        Key::Alt('o') => {
+            let outputs: Vec<SwayOutput> = list(write, read, Req::ListOutputs).await?;
+            let names = outputs.into_iter().map(|output| output.name).collect();
+            let output = pick("output> ", names)?;
+            Some(Req::MoveWindowToOutput { id, output })
+        }
+        _ => None,
+    }
+}
+
+/// The request for a tab action key. Pinning and muting toggle.
+fn tab_action(key: &Key, tab: &BrowserTab) -> Option<CliRequest> {
+    use CliRequest as Req;
+    let tab = BrowserTabRef {
+        tab_id: tab.id,
+        window_id: tab.window_id,
+    };
+    match key {
+        Key::Alt('c') => Some(Req::CloseTab(tab)),
+        Key::Alt('r') => Some(Req::ReloadTab(tab)),
+        Key::Alt('d') => Some(Req::DuplicateTab(tab)),
+        Key::Alt('p') => Some(Req::PinTab { tab, pinned: None }),
+        Key::Alt('u') => Some(Req::MuteTab { tab, muted: None }),
+        _ => None,
+    }
+}
+
+async fn list<T: DeserializeOwned>(
+    write: &mut SinkHole,
+    read: &mut Source,
+    req: CliRequest,
+) -> Option<T> {
+    let result = request(write, read, req)
+        .await
+        .and_then(|value| serde_json::from_value(value).map_err(|err| err.to_string()));
+    match result {
+        Ok(list) => Some(list),
+        Err(err) => {
+            report(Err(err));
+            None
+        }
+    }
+}
+
+/// Let the user pick one of the given names. What they typed is taken if nothing matches, e.g.
+/// to move a window to a new workspace.
+fn pick(prompt: &str, names: Vec<String>) -> Option<String> {
+    let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
+    for name in names {
+        tx_item.send(Arc::new(name)).unwrap();
+    }
+    drop(tx_item);
+
+    let options = SkimOptionsBuilder::default()
+        .multi(false)
+        .prompt(Some(prompt))
+        .build()
+        .unwrap();
+
+    let out = Skim::run_with(&options, Some(rx_item))?;
+    if out.final_key == Key::ESC {
+        return None;
+    }
+    match out.selected_items.first() {
+        Some(item) => Some(item.output().into_owned()),
+        None => Some(out.query).filter(|query| !query.is_empty()),
+    }
+}
+
+async fn run(tx_item: SkimItemSender) -> (SinkHole, Source) {
+    let width = std::env::var("DSKTPD_CLIENT_WIDTH")
+        .map(|w| usize::from_str_radix(&w, 10).unwrap_or(WIDTH))
+        .unwrap_or(WIDTH);
+
+    let (write, mut read) = connect().await;
+
+    // the daemon might send status updates before the client list, skip over those
+    let msg = loop {
+        let msg = read.next().await.expect("No response");
+
+        if let DesktopdMessage::ClientList { .. } = msg {
+            break msg;
+        }
+    };
 
     if let DesktopdMessage::ClientList { data } = msg {
-        let mut table = Table::new("{:<} {:<} {:<}");
+        let mut table = Table::new("{:<} {:<} {:<} {:<}");
 
         for item in &data {
             let row = match item {
@@ -71,10 +323,19 @@ async fn run(tx_item: SkimItemSender) -> SinkHole {
                         data.app_id.to_owned()
                     };
 
+                    // terminals show what runs in them, and where
+                    let name = match &data.process {
+                        Some(process) if Some(process.pid) != data.pid => {
+                            format!("{} ({} in {})", data.name, process.command(), process.cwd)
+                        }
+                        _ => data.name.to_owned(),
+                    };
+
                     Row::new()
                         .with_cell("app")
+                        .with_cell(&data.workspace)
                         .with_cell(&id_or_class)
-                        .with_cell(&data.name)
+                        .with_cell(name)
                 }
 
                 DesktopdClient::Tab { data } => {
@@ -91,9 +352,19 @@ async fn run(tx_item: SkimItemSender) -> SinkHole {
 
                     Row::new()
                         .with_cell("tab")
+                        .with_cell("")
                         .with_cell(formatted_title)
                         .with_cell(url.to_string())
                 }
+
+                DesktopdClient::Tmux { data } => Row::new()
+                    .with_cell("tmux")
+                    .with_cell(format!(
+                        "{}/{}:{}",
+                        data.server, data.session, data.window_index
+                    ))
+                    .with_cell(&data.current_command)
+                    .with_cell(&data.current_path),
             };
             table.add_row(row);
         }
@@ -109,50 +380,106 @@ async fn run(tx_item: SkimItemSender) -> SinkHole {
             .for_each(|wrap| tx_item.send(Arc::new(wrap)).unwrap())
     }
 
-    write
+    (write, read)
 }
 
 #[async_std::main]
 async fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("previous") => {
+            send_request(CliRequest::FocusPrevious).await;
+            return;
+        }
+        Some("next") => {
+            send_request(CliRequest::FocusNext).await;
+            return;
+        }
+        Some("pair") => {
+            if !send_request(CliRequest::PairBrowser).await {
+                return;
+            }
+            println!(
+                "Pairing is open for {} seconds, the browser extension will connect by itself.",
+                auth::PAIRING_WINDOW.as_secs()
+            );
+            return;
+        }
+        Some("open") => {
+            let url = std::env::args().nth(2).unwrap_or_else(|| {
+                eprintln!("Usage: dsktpd open <url>");
+                std::process::exit(2)
+            });
+            send_request(CliRequest::OpenUrl { url, window: None }).await;
+            return;
+        }
+        Some("watch") => {
+            watch(std::env::args().skip(2).collect()).await;
+            return;
+        }
+        _ => (),
+    }
+
     let (tx_item, rx_item): (SkimItemSender, SkimItemReceiver) = unbounded();
 
-    let mut write_handle = task::block_on(run(tx_item.clone()));
+    let (mut write_handle, mut read_handle) = task::block_on(run(tx_item.clone()));
 
     drop(tx_item);
 
     let options = SkimOptionsBuilder::default()
         .multi(false)
         .preview(None)
+        .expect(Some(ACTIONS.to_owned()))
+        .header(Some(HEADER))
         .build()
         .unwrap();
 
-    let selected_items = Skim::run_with(&options, Some(rx_item))
+    let (key, selected_items) = Skim::run_with(&options, Some(rx_item))
         .map(|out| match out.final_key {
-            Key::ESC => Vec::new(),
-            _ => out.selected_items,
+            Key::ESC => (Key::ESC, Vec::new()),
+            key => (key, out.selected_items),
         })
-        .unwrap_or_else(|| Vec::new());
+        .unwrap_or_else(|| (Key::ESC, Vec::new()));
 
     for item in selected_items.iter() {
         if let Some(wrapper) = (**item).as_any().downcast_ref::<Wrapper>() {
             use CliRequest as Req;
             use DesktopdClient as DC;
-            use DesktopdMessage as DM;
-            let command = match &wrapper.client {
-                DC::Window { data } => DM::CliRequest(Req::FocusWindow { id: data.id }),
+            let req = match (&key, &wrapper.client) {
+                (Key::Alt(_), DC::Window { data }) => {
+                    let action =
+                        window_action(&key, data.id, &mut write_handle, &mut read_handle).await;
+                    match action {
+                        Some(req) => req,
+                        None => continue,
+                    }
+                }
+
+                (Key::Alt(_), DC::Tab { data }) => match tab_action(&key, data) {
+                    Some(req) => req,
+                    None => continue,
+                },
 
-                DC::Tab { data } => DM::CliRequest(Req::FocusTab(BrowserTabRef {
+                (Key::Alt(_), _) => {
+                    report(Err(
+                        "Only windows and tabs can be closed or moved".to_owned()
+                    ));
+                    continue;
+                }
+
+                (_, DC::Window { data }) => Req::FocusWindow { id: data.id },
+
+                (_, DC::Tab { data }) => Req::FocusTab(BrowserTabRef {
                     tab_id: data.id,
                     window_id: data.window_id,
-                })),
-            };
+                }),
 
-            let msg = Message::Text(serde_json::to_string(&command).unwrap());
+                (_, DC::Tmux { data }) => Req::FocusTmuxPane {
+                    server: data.server.to_owned(),
+                    pane_id: data.pane_id.to_owned(),
+                },
+            };
 
-            write_handle
-                .send(msg)
-                .await
-                .expect("could not send message");
+            report(request(&mut write_handle, &mut read_handle, req).await);
         }
     }
 }
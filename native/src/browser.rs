@@ -1,15 +1,24 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct BrowserTab {
     pub id: usize,
     #[serde(rename = "windowId")]
     pub window_id: usize,
     pub title: String,
     pub url: String,
+    pub index: usize,
+    pub active: bool,
+    pub pinned: bool,
+    #[serde(default)]
+    pub audible: bool,
+    pub incognito: bool,
+    #[serde(rename = "favIconUrl", default)]
+    pub fav_icon_url: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct BrowserTabRef {
     #[serde(rename = "tabId")]
     pub tab_id: usize,
@@ -17,7 +26,7 @@ pub struct BrowserTabRef {
     pub window_id: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum BrowserResponse {
     #[serde(rename = "init")]
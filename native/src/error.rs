@@ -1,4 +1,5 @@
 use crate::message::DesktopdMessage;
+use crate::state::PeerAddr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,6 +7,15 @@ pub enum DesktopdError {
     #[error("Could not initialize connection.")]
     ConnectInitError,
 
+    #[error("Invalid or missing token.")]
+    AuthError,
+
+    #[error("{0} is not connected.")]
+    PeerGone(PeerAddr),
+
+    #[error("{0}")]
+    IncompatibleVersion(String),
+
     #[error(transparent)]
     WebSocketError(#[from] async_tungstenite::tungstenite::Error),
 
@@ -18,6 +28,9 @@ pub enum DesktopdError {
     #[error(transparent)]
     TimeoutError(#[from] async_std::future::TimeoutError),
 
+    #[error("tmux command failed: {0}")]
+    TmuxCommandError(String),
+
     #[error(transparent)]
     ChannelError(#[from] futures::channel::mpsc::TrySendError<DesktopdMessage>),
 }
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+/// Upper bound on the number of entries written to disk, so window titles that are only ever
+/// seen once don't make the file grow forever.
+const MAX_ENTRIES: usize = 2000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Visits {
+    pub count: u64,
+    pub last_visit: u64,
+}
+
+impl Visits {
+    /// Visit count weighted by how recently the last visit happened.
+    pub fn score(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.last_visit);
+        let weight = if age < HOUR {
+            4.0
+        } else if age < DAY {
+            2.0
+        } else if age < WEEK {
+            1.0
+        } else {
+            0.5
+        };
+        self.count as f64 * weight
+    }
+}
+
+/// Visit counts and recency per client, keyed by a stable identity that survives restarts of
+/// the daemon, sway and the browser.
+#[derive(Debug, Default)]
+pub struct Frecency {
+    path: Option<PathBuf>,
+    entries: HashMap<String, Visits>,
+    dirty: bool,
+}
+
+impl Frecency {
+    /// Scores that only live in memory.
+    pub fn new() -> Frecency {
+        Frecency::default()
+    }
+
+    /// `$XDG_STATE_HOME/desktopd/frecency.json`, falling back to `~/.local/state`.
+    pub fn default_path() -> Option<PathBuf> {
+        let state_home = env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+        Some(state_home.join("desktopd").join("frecency.json"))
+    }
+
+    /// Read scores from `path`. A missing file just means we start from scratch.
+    pub fn load(path: PathBuf) -> io::Result<Frecency> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Frecency {
+            path: Some(path),
+            entries,
+            dirty: false,
+        })
+    }
+
+    pub fn visit(&mut self, key: String, now: u64) {
+        let visits = self.entries.entry(key).or_default();
+        visits.count += 1;
+        visits.last_visit = now;
+        self.dirty = true;
+    }
+
+    pub fn score(&self, key: &str, now: u64) -> f64 {
+        self.entries
+            .get(key)
+            .map(|visits| visits.score(now))
+            .unwrap_or(0.0)
+    }
+
+    /// The scores to write to disk, if there is anything new to write. Serializing is cheap
+    /// enough to do while holding the state lock, the disk is left to `Unsaved::write`.
+    pub fn unsaved(&mut self) -> io::Result<Option<Unsaved>> {
+        let path = match (&self.path, self.dirty) {
+            (Some(path), true) => path.to_owned(),
+            _ => return Ok(None),
+        };
+
+        let now = unix_now();
+        if self.entries.len() > MAX_ENTRIES {
+            let mut scores = self
+                .entries
+                .iter()
+                .map(|(key, visits)| (visits.score(now), key.to_owned()))
+                .collect::<Vec<(f64, String)>>();
+            scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+            for (_, key) in scores.into_iter().skip(MAX_ENTRIES) {
+                self.entries.remove(&key);
+            }
+        }
+
+        let json = serde_json::to_vec(&self.entries)?;
+        self.dirty = false;
+        Ok(Some(Unsaved { path, json }))
+    }
+}
+
+/// Serialized scores on their way to disk.
+#[derive(Debug)]
+pub struct Unsaved {
+    path: PathBuf,
+    json: Vec<u8>,
+}
+
+impl Unsaved {
+    /// Replace the file atomically. It lists the titles and urls we visit, so it is only
+    /// readable by us, as is the temporary file it is written to first.
+    pub fn write(self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        // a leftover from an interrupted write may have been created with other permissions
+        match fs::remove_file(&tmp) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(&self.json)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0)
+}
@@ -1,46 +1,412 @@
+use crate::auth::TOKEN_HEADER;
+use crate::browser::BrowserTabRef;
 use crate::error::DesktopdError;
-use crate::message::{CliRequest, DesktopdMessage};
-use crate::state::{GlobalState, Tx};
+use crate::message::{
+    CliRequest, ConnectionType, DesktopdClient, DesktopdMessage, ErrorKind, Topic,
+};
+use crate::process::{self, ProcessInfo};
+use crate::schema;
+use crate::state::{ClientOrder, GlobalState, PeerAddr, State, Tx};
+use crate::sway::types::SwayWindow;
+use crate::websocket;
+use futures::channel::mpsc::unbounded;
+use futures::StreamExt;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
 use log::error;
-use log::info;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
 use std::env;
-use tide::Request;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tide::sse::Sender;
+use tide::utils::After;
+use tide::{Body, Next, Request, Response, StatusCode};
+use url::Url;
+
+/// A focus request may take the browser and sway a while, see `RetryPolicy`.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+enum ClientType {
+    #[serde(rename = "win")]
+    Window,
+    #[serde(rename = "tab")]
+    Tab,
+    #[serde(rename = "tmux")]
+    Tmux,
+}
+
+/// Filters for the client list. All given filters must match. Tabs are matched by the window
+/// showing them for `workspace`, `output` and `app_id`.
+#[derive(Deserialize, Debug, Default)]
+struct ClientQuery {
+    #[serde(rename = "type")]
+    client_type: Option<ClientType>,
+    workspace: Option<String>,
+    output: Option<String>,
+    app_id: Option<String>,
+    url_host: Option<String>,
+    /// Fuzzy matched like in the picker, against titles, urls, app ids and commands.
+    q: Option<String>,
+    #[serde(default)]
+    sort: ClientOrder,
+}
+
+impl ClientQuery {
+    fn matches(&self, state: &State, client: &DesktopdClient) -> bool {
+        self.matches_type(client)
+            && self.matches_container(state, client)
+            && self.matches_host(client)
+            && self.matches_text(client)
+    }
+
+    fn matches_type(&self, client: &DesktopdClient) -> bool {
+        let client_type = match client {
+            DesktopdClient::Window { .. } => ClientType::Window,
+            DesktopdClient::Tab { .. } => ClientType::Tab,
+            DesktopdClient::Tmux { .. } => ClientType::Tmux,
+        };
+        self.client_type.iter().all(|tipe| *tipe == client_type)
+    }
+
+    fn matches_container(&self, state: &State, client: &DesktopdClient) -> bool {
+        if self.workspace.is_none() && self.output.is_none() && self.app_id.is_none() {
+            return true;
+        }
+        match state.client_container(client) {
+            Some(win) => {
+                self.workspace.iter().all(|ws| ws == &win.workspace)
+                    && self.output.iter().all(|output| output == &win.output)
+                    && self
+                        .app_id
+                        .iter()
+                        .all(|app_id| app_id == &win.app_id || app_id == &win.class)
+            }
+            None => false,
+        }
+    }
+
+    fn matches_host(&self, client: &DesktopdClient) -> bool {
+        let host = match &self.url_host {
+            Some(host) => host,
+            None => return true,
+        };
+        match client {
+            DesktopdClient::Tab { data } => Url::parse(&data.url)
+                .ok()
+                .is_some_and(|url| url.host_str() == Some(host)),
+            _ => false,
+        }
+    }
+
+    fn matches_text(&self, client: &DesktopdClient) -> bool {
+        let pattern = match &self.q {
+            Some(pattern) => pattern,
+            None => return true,
+        };
+        let text = match client {
+            DesktopdClient::Window { data } => {
+                format!("{} {} {}", data.app_id, data.class, data.name)
+            }
+            DesktopdClient::Tab { data } => format!("{} {}", data.title, data.url),
+            DesktopdClient::Tmux { data } => format!(
+                "{} {} {} {}",
+                data.session, data.window_name, data.current_command, data.current_path
+            ),
+        };
+        SkimMatcherV2::default()
+            .fuzzy_match(&text, pattern)
+            .is_some()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct EventQuery {
+    /// Comma separated, all topics if not given.
+    topics: Option<String>,
+}
+
+impl EventQuery {
+    fn topics(&self) -> Result<Vec<Topic>, serde_json::Error> {
+        match &self.topics {
+            Some(topics) => topics
+                .split(',')
+                .map(|topic| serde_json::from_value(json!(topic.trim())))
+                .collect(),
+            None => Ok(Topic::ALL.to_vec()),
+        }
+    }
+}
+
+/// The JSON body of every error, with a status code to match its kind.
+fn error_response(kind: ErrorKind, message: &str) -> Response {
+    let status = match kind {
+        ErrorKind::NotFound => StatusCode::NotFound,
+        ErrorKind::NotConnected => StatusCode::ServiceUnavailable,
+        ErrorKind::Timeout => StatusCode::GatewayTimeout,
+        ErrorKind::InvalidRequest | ErrorKind::IncompatibleVersion => StatusCode::BadRequest,
+        ErrorKind::Unauthorized => StatusCode::Unauthorized,
+        ErrorKind::FocusFailed | ErrorKind::CommandFailed | ErrorKind::Internal => {
+            StatusCode::InternalServerError
+        }
+    };
+    let mut response = Response::new(status);
+    response.set_body(json!({ "kind": kind, "message": message }));
+    response
+}
+
+/// Turn errors raised by handlers with `?` (e.g. a malformed query or body) into JSON, like the
+/// ones the handlers build themselves.
+async fn json_errors(mut response: Response) -> tide::Result {
+    if let Some(err) = response.take_error() {
+        let kind = if err.status().is_client_error() {
+            ErrorKind::InvalidRequest
+        } else {
+            ErrorKind::Internal
+        };
+        let status = err.status();
+        response = error_response(kind, &err.to_string());
+        response.set_status(status);
+    }
+    Ok(response)
+}
+
+/// Routes anyone may read, as they describe the API rather than the desktop.
+pub const PUBLIC_ROUTES: &[&str] = &["/schema", "/openapi.json"];
+
+/// Turn away requests without the token, as the clients give away every url, title and command
+/// line.
+fn require_token<'a>(
+    req: Request<(GlobalState, Tx)>,
+    next: Next<'a, (GlobalState, Tx)>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        if PUBLIC_ROUTES.contains(&req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+
+        let token = req.header(TOKEN_HEADER).map(|values| values.as_str());
+        if !req.state().0.lock().unwrap().authenticate(token) {
+            warn!(
+                "Rejected request for {} from {}: invalid or missing token",
+                req.url().path(),
+                req.remote().unwrap_or("unknown peer")
+            );
+            return Ok(error_response(
+                ErrorKind::Unauthorized,
+                "Invalid or missing token",
+            ));
+        }
+        Ok(next.run(req).await)
+    })
+}
+
+fn param<T: FromStr>(req: &Request<(GlobalState, Tx)>, name: &str) -> Option<T> {
+    req.param(name).ok()?.parse().ok()
+}
 
 pub async fn get_clients(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let query: ClientQuery = req.query()?;
     let (state, _tx) = req.state();
-    let clients = { state.lock().unwrap().clients() };
-    let json = serde_json::to_string(&clients).expect("Could not serialzie client list");
-    Ok(json.into())
+    let clients = {
+        let state = state.lock().unwrap();
+        state
+            .clients(query.sort)
+            .into_iter()
+            .filter(|client| query.matches(&state, client))
+            .collect::<Vec<DesktopdClient>>()
+    };
+    Ok(Body::from_json(&clients)?.into())
 }
 
+pub async fn get_window(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let id = match param(&req, "id") {
+        Some(id) => id,
+        None => {
+            return Ok(error_response(
+                ErrorKind::InvalidRequest,
+                "Invalid window id",
+            ))
+        }
+    };
+    let (state, _tx) = req.state();
+    let window = { state.lock().unwrap().window(&id).cloned() };
+    match window {
+        Some(win) => {
+            // worth looking up afresh for a single window, but not by scanning all of /proc
+            let process = win
+                .pid
+                .and_then(|pid| ProcessInfo::read(process::foreground(pid)));
+            Ok(Body::from_json(&SwayWindow { process, ..win })?.into())
+        }
+        None => Ok(error_response(ErrorKind::NotFound, "No such window")),
+    }
+}
+
+pub async fn get_tab(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let tab = match (param(&req, "window_id"), param(&req, "tab_id")) {
+        (Some(window_id), Some(tab_id)) => BrowserTabRef { tab_id, window_id },
+        _ => return Ok(error_response(ErrorKind::InvalidRequest, "Invalid tab id")),
+    };
+    let (state, _tx) = req.state();
+    let tab = { state.lock().unwrap().find_tab(&tab).cloned() };
+    match tab {
+        Some(tab) => Ok(Body::from_json(&tab)?.into()),
+        None => Ok(error_response(ErrorKind::NotFound, "No such tab")),
+    }
+}
+
+pub async fn get_workspaces(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let (state, _tx) = req.state();
+    let body = { Body::from_json(state.lock().unwrap().workspaces())? };
+    Ok(body.into())
+}
+
+pub async fn get_outputs(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let (state, _tx) = req.state();
+    let body = { Body::from_json(state.lock().unwrap().outputs())? };
+    Ok(body.into())
+}
+
+pub async fn get_binding(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let (state, _tx) = req.state();
+    let body = { Body::from_json(&state.lock().unwrap().last_binding())? };
+    Ok(body.into())
+}
+
+pub async fn get_status(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let (state, _tx) = req.state();
+    let status = { state.lock().unwrap().sway_status() };
+    let body = Body::from_json(&json!({ "sway": status }))?;
+    Ok(body.into())
+}
+
+pub async fn get_schema(_req: Request<(GlobalState, Tx)>) -> tide::Result {
+    Ok(Body::from_json(&schema::protocol())?.into())
+}
+
+pub async fn get_openapi(_req: Request<(GlobalState, Tx)>) -> tide::Result {
+    Ok(Body::from_json(&schema::openapi())?.into())
+}
+
+/// Stream events as they happen, e.g. `curl -N localhost:8081/events?topics=focus,clients`. Every
+/// event is named after its `msg_type` and carries the whole message as JSON.
+pub async fn get_events(req: Request<(GlobalState, Tx)>) -> tide::Result {
+    let query: EventQuery = req.query()?;
+    let topics = match query.topics() {
+        Ok(topics) => topics,
+        Err(err) => return Ok(error_response(ErrorKind::InvalidRequest, &err.to_string())),
+    };
+
+    Ok(tide::sse::upgrade(req, move |req, sender| {
+        stream_events(req, sender, topics.clone())
+    }))
+}
+
+async fn stream_events(
+    req: Request<(GlobalState, Tx)>,
+    sender: Sender,
+    topics: Vec<Topic>,
+) -> tide::Result<()> {
+    let (state, _tx) = req.state();
+    let addr = PeerAddr::Http(NEXT_REQUEST.fetch_add(1, Ordering::Relaxed));
+    let (peer_tx, mut peer_rx) = unbounded();
+    {
+        let mut state = state.lock().unwrap();
+        state.add_peer(ConnectionType::Cli, addr, peer_tx);
+        state.subscribe(addr, topics);
+    }
+    let remote = req.remote().unwrap_or("unknown peer");
+    info!("Streaming events to {}", remote);
+
+    // a client that went away is only noticed when the next event can't be sent
+    let result = async {
+        while let Some(msg) = peer_rx.next().await {
+            // the same JSON as on the websocket, so sent as is rather than from a `Value`
+            let data = serde_json::to_string(&msg)?;
+            let name = serde_json::to_value(&msg)?["msg_type"]
+                .as_str()
+                .unwrap_or("message")
+                .to_owned();
+            sender.send(&name, data, None).await?;
+        }
+        Ok(())
+    }
+    .await;
+
+    state.lock().unwrap().remove_peer(&addr);
+    info!("Stopped streaming events to {}", remote);
+    result
+}
+
+/// Run a command, answering with its result once it has been carried out. The request takes
+/// part in the protocol as a peer of its own for as long as it waits for the reply.
 pub async fn post_command(mut req: Request<(GlobalState, Tx)>) -> tide::Result {
     let msg: CliRequest = req.body_json().await?;
     let (state, tx) = req.state();
 
-    use CliRequest::*;
-    match msg {
-        FocusWindow { .. } => tx
-            .unbounded_send(DesktopdMessage::CliRequest(msg.clone()))
-            .expect("Sending message failed"),
-        FocusTab { .. } => {
-            for (peer_addr, peer) in state.lock().unwrap().get_browser_connections() {
-                match peer.unbounded_send(DesktopdMessage::CliRequest(msg.clone())) {
-                    Ok(_) => info!("Successfully sent focus-tab message to browsers"),
-                    Err(e) => error!("Could not send message to browser {}: {}", peer_addr, e),
-                }
+    let request_id = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    let addr = PeerAddr::Http(request_id);
+    let (peer_tx, mut peer_rx) = unbounded();
+    state
+        .lock()
+        .unwrap()
+        .add_peer(ConnectionType::Cli, addr, peer_tx);
+
+    let reply = async {
+        websocket::handle_cli_request(state.clone(), tx.clone(), &addr, msg, Some(request_id))?;
+        // status updates are sent to all cli peers, skip over those
+        while let Some(msg) = peer_rx.next().await {
+            match msg {
+                DesktopdMessage::Response { result, .. } => return Ok(Ok(result)),
+                DesktopdMessage::Error { kind, message, .. } => return Ok(Err((kind, message))),
+                _ => (),
             }
         }
-    }
+        Err(DesktopdError::PeerGone(addr))
+    };
+    let reply = async_std::future::timeout(REPLY_TIMEOUT, reply).await;
+    state.lock().unwrap().remove_peer(&addr);
 
-    Ok("Ok".into())
+    let result = match reply {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => {
+            error!("Could not handle command: {}", err);
+            return Ok(error_response(ErrorKind::Internal, &err.to_string()));
+        }
+        Err(_) => Err((ErrorKind::Timeout, "No reply in time".to_owned())),
+    };
+
+    match result {
+        Ok(result) => Ok(Body::from_json(&result)?.into()),
+        Err((kind, message)) => Ok(error_response(kind, &message)),
+    }
 }
 
 pub async fn run(state: GlobalState, sway_tx: Tx) -> Result<(), DesktopdError> {
     let addr = env::args().nth(2).unwrap_or("127.0.0.1:8081".to_owned());
     let mut app = tide::with_state((state, sway_tx));
+    app.with(After(json_errors));
+    app.with(require_token);
 
     app.at("/clients").get(get_clients);
+    app.at("/windows/:id").get(get_window);
+    app.at("/tabs/:window_id/:tab_id").get(get_tab);
+    app.at("/status").get(get_status);
+    app.at("/workspaces").get(get_workspaces);
+    app.at("/outputs").get(get_outputs);
+    app.at("/binding").get(get_binding);
     app.at("/cmd").post(post_command);
+    app.at("/events").get(get_events);
+    app.at("/schema").get(get_schema);
+    app.at("/openapi.json").get(get_openapi);
 
     app.listen(addr).await?;
     Ok(())
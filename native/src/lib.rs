@@ -11,10 +11,16 @@ extern crate thiserror;
 extern crate tide;
 extern crate url;
 
+pub mod auth;
 pub mod browser;
 pub mod error;
+pub mod frecency;
 pub mod http;
 pub mod message;
+pub mod process;
+pub mod retry;
+pub mod schema;
+pub mod socket;
 pub mod state;
 pub mod sway;
 pub mod tmux;
@@ -1,9 +1,26 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::browser::*;
+use crate::state::PeerAddr;
 use crate::sway::types::*;
+use crate::tmux::types::TmuxClient;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The version of the protocol spoken by this build. Bumped whenever a change to the messages
+/// would make an older peer misread them.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version the daemon still accepts from peers.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the daemon, announced to peers in `Welcome`.
+pub const CAPABILITIES: &[&str] = &["request_id", "pairing", "tmux", "subscribe", "tab_commands"];
+
+/// The capability a browser extension announces if it carries out tab commands, such as
+/// `CloseTab`, and acknowledges them.
+pub const TAB_COMMANDS: &str = "tab_commands";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "application")]
 pub enum ConnectionType {
     #[serde(rename = "browser")]
@@ -25,36 +42,207 @@ impl PartialEq for ConnectionType {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "browser_request")]
 pub enum BrowserRequest {
     #[serde(rename = "focus_tab")]
     FocusTab(BrowserTabRef),
+    #[serde(rename = "tag_window")]
+    TagWindow {
+        #[serde(rename = "windowId")]
+        window_id: usize,
+        nonce: String,
+    },
+    #[serde(rename = "close_tab")]
+    CloseTab(BrowserTabRef),
+    #[serde(rename = "reload_tab")]
+    ReloadTab(BrowserTabRef),
+    /// Answered with the `BrowserTabRef` of the copy.
+    #[serde(rename = "duplicate_tab")]
+    DuplicateTab(BrowserTabRef),
+    #[serde(rename = "pin_tab")]
+    PinTab {
+        #[serde(flatten)]
+        tab: BrowserTabRef,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pinned: Option<bool>,
+    },
+    #[serde(rename = "mute_tab")]
+    MuteTab {
+        #[serde(flatten)]
+        tab: BrowserTabRef,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        muted: Option<bool>,
+    },
+    #[serde(rename = "move_tab_to_window")]
+    MoveTabToWindow {
+        #[serde(flatten)]
+        tab: BrowserTabRef,
+        #[serde(rename = "toWindowId")]
+        to_window_id: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+    },
+    /// Answered with the `BrowserTabRef` of the new tab.
+    #[serde(rename = "open_url")]
+    OpenUrl {
+        url: String,
+        #[serde(rename = "windowId", default, skip_serializing_if = "Option::is_none")]
+        window_id: Option<usize>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "cli_request")]
 pub enum CliRequest {
     #[serde(rename = "focus_tab")]
     FocusTab(BrowserTabRef),
     #[serde(rename = "focus_window")]
     FocusWindow { id: usize },
+    #[serde(rename = "list_workspaces")]
+    ListWorkspaces,
+    #[serde(rename = "list_outputs")]
+    ListOutputs,
+    #[serde(rename = "last_binding")]
+    LastBinding,
+    #[serde(rename = "resync")]
+    Resync,
+    #[serde(rename = "focus_previous")]
+    FocusPrevious,
+    #[serde(rename = "focus_next")]
+    FocusNext,
+    #[serde(rename = "focus_tmux_pane")]
+    FocusTmuxPane { server: String, pane_id: String },
+    #[serde(rename = "close_window")]
+    CloseWindow { id: usize },
+    #[serde(rename = "move_window_to_workspace")]
+    MoveWindowToWorkspace { id: usize, workspace: String },
+    #[serde(rename = "move_window_to_output")]
+    MoveWindowToOutput { id: usize, output: String },
+    #[serde(rename = "toggle_floating")]
+    ToggleFloating { id: usize },
+    #[serde(rename = "toggle_fullscreen")]
+    ToggleFullscreen { id: usize },
+    #[serde(rename = "send_to_scratchpad")]
+    SendToScratchpad { id: usize },
+    #[serde(rename = "close_tab")]
+    CloseTab(BrowserTabRef),
+    #[serde(rename = "reload_tab")]
+    ReloadTab(BrowserTabRef),
+    /// Answered with the `BrowserTabRef` of the copy.
+    #[serde(rename = "duplicate_tab")]
+    DuplicateTab(BrowserTabRef),
+    /// Pins or unpins a tab, toggling it if `pinned` isn't given.
+    #[serde(rename = "pin_tab")]
+    PinTab {
+        #[serde(flatten)]
+        tab: BrowserTabRef,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pinned: Option<bool>,
+    },
+    /// Mutes or unmutes a tab, toggling it if `muted` isn't given.
+    #[serde(rename = "mute_tab")]
+    MuteTab {
+        #[serde(flatten)]
+        tab: BrowserTabRef,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        muted: Option<bool>,
+    },
+    /// Moves a tab to another window of the same browser, to the end unless `index` is given.
+    #[serde(rename = "move_tab_to_window")]
+    MoveTabToWindow {
+        #[serde(flatten)]
+        tab: BrowserTabRef,
+        #[serde(rename = "toWindowId")]
+        to_window_id: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+    },
+    /// Opens a url in a new tab of the given browser window, or of the browser last used.
+    /// Answered with the `BrowserTabRef` of the new tab.
+    #[serde(rename = "open_url")]
+    OpenUrl {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        window: Option<usize>,
+    },
+    #[serde(rename = "pair_browser")]
+    PairBrowser,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 #[serde(tag = "client_type")]
 pub enum DesktopdClient {
     #[serde(rename = "win")]
     Window { data: SwayWindow },
     #[serde(rename = "tab")]
     Tab { data: BrowserTab },
+    #[serde(rename = "tmux")]
+    Tmux { data: TmuxClient },
+}
+
+/// Identifies a client without carrying its data, e.g. in the focus history.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "client_type")]
+pub enum ClientRef {
+    #[serde(rename = "win")]
+    Window { id: usize },
+    #[serde(rename = "tab")]
+    Tab(BrowserTabRef),
+}
+
+/// What a peer can subscribe to, to be kept up to date without asking.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// Windows and tmux panes coming, going and changing.
+    Clients,
+    /// The client with focus.
+    Focus,
+    /// Browser tabs coming, going and changing, including the active ones.
+    Tabs,
+    /// The workspace list, whenever it changes.
+    Workspaces,
+    /// Browsers and command line clients connecting and disconnecting.
+    Peers,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Topic {
+    pub const ALL: &'static [Topic] = &[
+        Topic::Clients,
+        Topic::Focus,
+        Topic::Tabs,
+        Topic::Workspaces,
+        Topic::Peers,
+    ];
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "msg_type")]
 pub enum DesktopdMessage {
+    /// The first message of every peer. Peers that send no version predate versioning.
     #[serde(rename = "connect")]
-    Connect(ConnectionType),
+    Connect {
+        #[serde(flatten)]
+        conn: ConnectionType,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<String>,
+    },
+
+    /// The daemon's answer to an accepted `Connect`.
+    #[serde(rename = "welcome")]
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+
+    /// Sent to a browser that connected without a token while pairing was open.
+    #[serde(rename = "paired")]
+    Paired { token: String },
 
     #[serde(rename = "disconnect")]
     Disconnect(ConnectionType),
@@ -62,14 +250,162 @@ pub enum DesktopdMessage {
     #[serde(rename = "browser_message")]
     BrowserMessage { data: BrowserResponse },
 
+    /// A request to a browser. Browsers acknowledge those with a `request_id` by answering
+    /// with a `Response` or an `Error` carrying the same id.
     #[serde(rename = "browser_request")]
-    BrowserRequest(BrowserRequest),
+    BrowserRequest {
+        #[serde(flatten)]
+        request: BrowserRequest,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+    },
 
+    /// A request, optionally with an id to tell its `Response` or `Error` apart. `origin` is only
+    /// set on requests passed on inside the daemon, so the reply can be sent to the right peer.
     #[serde(rename = "cli_request")]
-    CliRequest(CliRequest),
+    CliRequest {
+        #[serde(flatten)]
+        request: CliRequest,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+        #[serde(skip)]
+        origin: Option<PeerAddr>,
+    },
+
+    #[serde(rename = "response")]
+    Response {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+        result: serde_json::Value,
+    },
 
     #[serde(rename = "client_list")]
     ClientList { data: Vec<DesktopdClient> },
+
+    #[serde(rename = "workspace_list")]
+    WorkspaceList { data: Vec<SwayWorkspace> },
+
+    #[serde(rename = "output_list")]
+    OutputList { data: Vec<SwayOutput> },
+
+    #[serde(rename = "binding")]
+    Binding { data: Option<SwayBinding> },
+
+    #[serde(rename = "sway_status")]
+    SwayStatus { status: SwayStatus },
+
+    /// Replaces the topics a peer is subscribed to. An empty list unsubscribes from everything.
+    #[serde(rename = "subscribe")]
+    Subscribe { topics: Vec<Topic> },
+
+    #[serde(rename = "client_added")]
+    ClientAdded { data: DesktopdClient },
+
+    /// Carries the client as it was last seen.
+    #[serde(rename = "client_removed")]
+    ClientRemoved { data: DesktopdClient },
+
+    #[serde(rename = "client_updated")]
+    ClientUpdated { data: DesktopdClient },
+
+    #[serde(rename = "focus_changed")]
+    FocusChanged { data: ClientRef },
+
+    #[serde(rename = "peer_connected")]
+    PeerConnected {
+        peer: String,
+        #[serde(flatten)]
+        conn: ConnectionType,
+    },
+
+    #[serde(rename = "peer_disconnected")]
+    PeerDisconnected {
+        peer: String,
+        #[serde(flatten)]
+        conn: ConnectionType,
+    },
+
+    #[serde(rename = "error")]
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<u64>,
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The window showing a tab could not be found in time.
+    FocusFailed,
+    /// There is no such client, or nothing to switch to.
+    NotFound,
+    /// The browser, sway or tmux is needed for the request but not connected.
+    NotConnected,
+    /// sway or tmux refused a command.
+    CommandFailed,
+    /// No reply came in time.
+    Timeout,
+    /// The peer speaks a protocol version the daemon doesn't.
+    IncompatibleVersion,
+    /// The request could not be understood, e.g. a malformed query.
+    InvalidRequest,
+    /// The token is invalid or missing.
+    Unauthorized,
+    /// Something went wrong in the daemon itself.
+    Internal,
+}
+
+/// The outcome of a request: some data (or null) for a `Response`, or what went wrong for an
+/// `Error`.
+pub type RequestResult = Result<serde_json::Value, (ErrorKind, String)>;
+
+impl DesktopdMessage {
+    /// A request nobody is waiting on a reply for.
+    pub fn cli_request(request: CliRequest) -> DesktopdMessage {
+        DesktopdMessage::CliRequest {
+            request,
+            request_id: None,
+            origin: None,
+        }
+    }
+
+    /// The `Connect` message of this build, announcing its protocol version.
+    pub fn connect(
+        conn: ConnectionType,
+        token: Option<String>,
+        capabilities: &[&str],
+    ) -> DesktopdMessage {
+        DesktopdMessage::Connect {
+            conn,
+            token,
+            version: Some(PROTOCOL_VERSION),
+            capabilities: capabilities.iter().map(|cap| cap.to_string()).collect(),
+        }
+    }
+
+    pub fn welcome() -> DesktopdMessage {
+        DesktopdMessage::Welcome {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|cap| cap.to_string()).collect(),
+        }
+    }
+}
+
+/// Whether the daemon can talk to a peer that connected with the given protocol version.
+pub fn check_version(version: Option<u32>) -> Result<(), String> {
+    match version {
+        Some(version) if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => Ok(()),
+        Some(version) => Err(format!(
+            "Protocol version {} is not supported, desktopd speaks versions {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )),
+        None => Err(format!(
+            "No protocol version given, desktopd speaks versions {} to {}; please update",
+            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )),
+    }
 }
 
 impl ConnectionType {
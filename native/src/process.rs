@@ -0,0 +1,216 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// What a process is running, as read from `/proc`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub exe: String,
+    pub cmdline: Vec<String>,
+    pub cwd: String,
+}
+
+impl ProcessInfo {
+    /// Fields we aren't allowed to read are left empty, only a process that is gone yields
+    /// `None`.
+    pub fn read(pid: u32) -> Option<ProcessInfo> {
+        let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        let link = |name: &str| {
+            fs::read_link(format!("/proc/{}/{}", pid, name))
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+
+        Some(ProcessInfo {
+            pid,
+            exe: link("exe"),
+            cmdline: cmdline
+                .split(|byte| *byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect(),
+            cwd: link("cwd"),
+        })
+    }
+
+    /// The name of the command, without its path.
+    pub fn command(&self) -> &str {
+        let arg0 = self
+            .cmdline
+            .first()
+            .map(String::as_str)
+            .unwrap_or(&self.exe);
+        arg0.rsplit('/').next().unwrap_or(arg0)
+    }
+}
+
+/// The fields of `/proc/<pid>/stat` we care about.
+#[derive(Debug)]
+struct Stat {
+    ppid: u32,
+    tty_nr: i32,
+    tpgid: i32,
+}
+
+fn stat(pid: u32) -> Option<Stat> {
+    parse_stat(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+fn parse_stat(stat: &str) -> Option<Stat> {
+    // the command name is in parentheses and may itself contain spaces or parentheses
+    let fields = stat[stat.rfind(')')? + 2..]
+        .split(' ')
+        .collect::<Vec<&str>>();
+    Some(Stat {
+        ppid: fields.get(1)?.parse().ok()?,
+        tty_nr: fields.get(4)?.parse().ok()?,
+        tpgid: fields.get(5)?.parse().ok()?,
+    })
+}
+
+/// The parent of a process, read from `/proc/<pid>/stat`.
+pub fn parent_pid(pid: u32) -> Option<u32> {
+    stat(pid).map(|stat| stat.ppid)
+}
+
+/// `pid` followed by its parent, grandparent and so on, up to (but excluding) init.
+pub fn ancestors(pid: u32) -> Vec<u32> {
+    let mut pids = vec![pid];
+    while let Some(parent) = pids.last().and_then(|pid| parent_pid(*pid)) {
+        if parent <= 1 || pids.contains(&parent) {
+            break;
+        }
+        pids.push(parent);
+    }
+    pids
+}
+
+/// The process the user is looking at in the window of process `pid`, see
+/// `ProcessTree::foreground`. Only reads the children of `pid`, for when a single window is
+/// looked up.
+pub fn foreground(pid: u32) -> u32 {
+    let children = fs::read_dir(format!("/proc/{}/task", pid))
+        .map(|tasks| {
+            tasks
+                .flatten()
+                .filter_map(|task| fs::read_to_string(task.path().join("children")).ok())
+                .flat_map(|children| {
+                    children
+                        .split_whitespace()
+                        .filter_map(|child| child.parse().ok())
+                        .collect::<Vec<u32>>()
+                })
+                .filter_map(|child| Some((child, stat(child)?)))
+                .collect::<Vec<(u32, Stat)>>()
+        })
+        .unwrap_or_default();
+    foreground_of(pid, &children)
+}
+
+/// A terminal's shell has the terminal as its controlling tty, and knows which process group
+/// is in its foreground.
+fn foreground_of(pid: u32, children: &[(u32, Stat)]) -> u32 {
+    children
+        .iter()
+        .find(|(_, stat)| stat.tty_nr != 0 && stat.tpgid > 0)
+        .map(|(_, stat)| stat.tpgid as u32)
+        .unwrap_or(pid)
+}
+
+/// A snapshot of which processes are running, so that the children of many processes can be
+/// looked up without scanning `/proc` for each of them.
+#[derive(Debug, Default)]
+pub struct ProcessTree {
+    children: HashMap<u32, Vec<(u32, Stat)>>,
+}
+
+impl ProcessTree {
+    pub fn read() -> ProcessTree {
+        let mut children = HashMap::<u32, Vec<(u32, Stat)>>::new();
+        let entries = match fs::read_dir("/proc") {
+            Ok(entries) => entries,
+            Err(_) => return ProcessTree::default(),
+        };
+
+        for entry in entries.flatten() {
+            let pid = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if let Some(stat) = stat(pid) {
+                children.entry(stat.ppid).or_default().push((pid, stat));
+            }
+        }
+        ProcessTree { children }
+    }
+
+    /// The process the user is looking at in the window of process `pid`. For a terminal this
+    /// is whatever runs in the foreground of the terminal of its shell, for anything else it is
+    /// the process itself.
+    pub fn foreground(&self, pid: u32) -> u32 {
+        let children = self
+            .children
+            .get(&pid)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        foreground_of(pid, children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(ppid: u32, tty_nr: i32, tpgid: i32) -> Stat {
+        Stat {
+            ppid,
+            tty_nr,
+            tpgid,
+        }
+    }
+
+    #[test]
+    fn parse_stat_line() {
+        let line = "4242 (bash) S 4200 4242 4242 34817 4300 4194304 1519 0 0 0 2 1 0 0 20 0 1 0";
+        let stat = parse_stat(line).unwrap();
+        assert_eq!((stat.ppid, stat.tty_nr, stat.tpgid), (4200, 34817, 4300));
+    }
+
+    #[test]
+    fn parse_stat_odd_command_names() {
+        let line = "17 (tmux: server) S 1 17 17 0 -1 4194368 9 0 0 0 0 0 0 0 20 0 1 0";
+        let stat = parse_stat(line).unwrap();
+        assert_eq!((stat.ppid, stat.tty_nr, stat.tpgid), (1, 0, -1));
+
+        let line = "18 (a) b (c)) R 17 18 18 34818 18 4194304 0 0 0 0 0 0 0 0 20 0 1 0";
+        let stat = parse_stat(line).unwrap();
+        assert_eq!((stat.ppid, stat.tty_nr, stat.tpgid), (17, 34818, 18));
+
+        assert!(parse_stat("19 (truncated").is_none());
+        assert!(parse_stat("20 (short) S 1").is_none());
+    }
+
+    #[test]
+    fn foreground_of_terminal() {
+        // a terminal running a daemon of its own and a shell, which runs vim in the foreground
+        let tree = ProcessTree {
+            children: HashMap::from([
+                (
+                    100,
+                    vec![(101, stat(100, 0, -1)), (102, stat(100, 34817, 110))],
+                ),
+                (102, vec![(110, stat(102, 34817, 110))]),
+            ]),
+        };
+        assert_eq!(tree.foreground(100), 110);
+        // processes without a shell below them are what the user looks at
+        assert_eq!(tree.foreground(110), 110);
+        assert_eq!(tree.foreground(200), 200);
+    }
+}
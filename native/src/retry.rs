@@ -0,0 +1,186 @@
+use std::time::{Duration, Instant};
+
+/// Source of the current time, so retry schedules can be driven by a fake clock.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How often and for how long to retry an operation. The delay doubles with every attempt, up
+/// to `max_delay`, and no attempt is scheduled past the `deadline`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(500),
+            deadline: Duration::from_secs(2),
+        }
+    }
+}
+
+pub struct Retry<C: Clock = SystemClock> {
+    policy: RetryPolicy,
+    clock: C,
+    started: Instant,
+    attempts: usize,
+}
+
+impl Retry<SystemClock> {
+    pub fn new(policy: RetryPolicy) -> Retry<SystemClock> {
+        Retry::with_clock(policy, SystemClock)
+    }
+}
+
+impl<C: Clock> Retry<C> {
+    pub fn with_clock(policy: RetryPolicy, clock: C) -> Retry<C> {
+        let started = clock.now();
+        Retry {
+            policy,
+            clock,
+            started,
+            attempts: 0,
+        }
+    }
+
+    /// Number of attempts made so far (including the first one).
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// Record a failed attempt and return how long to wait before the next one, or `None` if
+    /// the policy is exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        if self.attempts >= self.policy.max_attempts {
+            return None;
+        }
+
+        let factor = 2u32.saturating_pow(self.attempts as u32 - 1);
+        let delay = self
+            .policy
+            .initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.policy.max_delay)
+            .min(self.policy.max_delay);
+
+        let elapsed = self.clock.now().saturating_duration_since(self.started);
+        if elapsed.saturating_add(delay) > self.policy.deadline {
+            None
+        } else {
+            Some(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn new() -> FakeClock {
+            FakeClock(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 100,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            deadline: Duration::from_secs(60),
+        }
+    }
+
+    fn delays(retry: &mut Retry<FakeClock>, count: usize) -> Vec<Option<u128>> {
+        (0..count)
+            .map(|_| retry.next_delay().map(|delay| delay.as_millis()))
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let mut retry = Retry::with_clock(policy(), FakeClock::new());
+        assert_eq!(
+            delays(&mut retry, 7),
+            vec![
+                Some(10),
+                Some(20),
+                Some(40),
+                Some(80),
+                Some(100),
+                Some(100),
+                Some(100)
+            ]
+        );
+        assert_eq!(retry.attempts(), 7);
+    }
+
+    #[test]
+    fn huge_factors_are_capped() {
+        let mut retry = Retry::with_clock(policy(), FakeClock::new());
+        delays(&mut retry, 70);
+        assert_eq!(retry.next_delay(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..policy()
+        };
+        let mut retry = Retry::with_clock(policy, FakeClock::new());
+        assert_eq!(delays(&mut retry, 4), vec![Some(10), Some(20), None, None]);
+    }
+
+    #[test]
+    fn deadline() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy {
+            deadline: Duration::from_millis(1000),
+            ..policy()
+        };
+        let mut retry = Retry::with_clock(policy, clock.clone());
+        assert_eq!(retry.next_delay(), Some(Duration::from_millis(10)));
+
+        // the next delay (20ms) would still end in time
+        clock.advance(Duration::from_millis(980));
+        assert_eq!(retry.next_delay(), Some(Duration::from_millis(20)));
+
+        // but this one (40ms) would not
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(retry.next_delay(), None);
+    }
+}
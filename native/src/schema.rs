@@ -0,0 +1,252 @@
+//! Descriptions of the wire format for clients written in other languages, derived from the
+//! types themselves: a JSON Schema of the protocol, and an OpenAPI description of the HTTP API.
+//! Snapshots of both are kept in `schema/`, written by the `desktopd-schema` binary and checked
+//! by the tests below.
+
+use crate::auth::TOKEN_HEADER;
+use crate::browser::*;
+use crate::http::PUBLIC_ROUTES;
+use crate::message::*;
+use crate::state::ClientOrder;
+use crate::sway::types::*;
+use schemars::gen::SchemaSettings;
+use schemars::schema::RootSchema;
+use serde_json::{json, Value};
+
+/// The messages spoken on the websocket and the unix socket. `DesktopdMessage` is the root, the
+/// types it is made of (e.g. `CliRequest` and `DesktopdClient`) are among its definitions.
+pub fn protocol() -> RootSchema {
+    let mut gen = SchemaSettings::draft07().into_generator();
+    gen.subschema_for::<CliRequest>();
+    gen.subschema_for::<BrowserResponse>();
+    gen.subschema_for::<DesktopdClient>();
+    gen.into_root_schema_for::<DesktopdMessage>()
+}
+
+/// The routes served by `http::run`.
+pub fn openapi() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let client = json!(gen.subschema_for::<DesktopdClient>());
+    let window = json!(gen.subschema_for::<SwayWindow>());
+    let tab = json!(gen.subschema_for::<BrowserTab>());
+    let workspace = json!(gen.subschema_for::<SwayWorkspace>());
+    let output = json!(gen.subschema_for::<SwayOutput>());
+    let binding = json!(gen.subschema_for::<Option<SwayBinding>>());
+    let status = json!(gen.subschema_for::<SwayStatus>());
+    let request = json!(gen.subschema_for::<CliRequest>());
+    let order = json!(gen.subschema_for::<ClientOrder>());
+    let kind = json!(gen.subschema_for::<ErrorKind>());
+    gen.subschema_for::<DesktopdMessage>();
+
+    let mut schemas = json!(gen.take_definitions());
+    schemas["HttpError"] = json!({
+        "type": "object",
+        "required": ["kind", "message"],
+        "properties": { "kind": kind, "message": { "type": "string" } },
+    });
+
+    let string = json!({ "type": "string" });
+    let client_type = json!({
+        "type": "string",
+        "enum": ["win", "tab", "tmux"],
+    });
+
+    let mut openapi = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "desktopd",
+            "version": env!("CARGO_PKG_VERSION"),
+            "x-protocol-version": PROTOCOL_VERSION,
+        },
+        "paths": {
+            "/clients": { "get": {
+                "summary": "Windows, tabs and tmux panes, like in the picker",
+                "parameters": [
+                    query("type", "Only clients of this type", client_type),
+                    query("workspace", "Only clients on this workspace", string.clone()),
+                    query("output", "Only clients on this output", string.clone()),
+                    query("app_id", "Only clients in windows of this app", string.clone()),
+                    query("url_host", "Only tabs with this host", string.clone()),
+                    query("q", "Fuzzy matched like in the picker", string.clone()),
+                    query("sort", "The order of the list", order),
+                ],
+                "responses": {
+                    "200": ok("The matching clients", array(client)),
+                    "400": error("A malformed query"),
+                },
+            }},
+            "/windows/{id}": { "get": {
+                "summary": "A sway window",
+                "parameters": [path("id")],
+                "responses": {
+                    "200": ok("The window", window),
+                    "400": error("A malformed id"),
+                    "404": error("No such window"),
+                },
+            }},
+            "/tabs/{window_id}/{tab_id}": { "get": {
+                "summary": "A browser tab",
+                "parameters": [path("window_id"), path("tab_id")],
+                "responses": {
+                    "200": ok("The tab", tab),
+                    "400": error("A malformed id"),
+                    "404": error("No such tab"),
+                },
+            }},
+            "/workspaces": { "get": {
+                "summary": "Sway's workspaces",
+                "responses": { "200": ok("The workspaces", array(workspace)) },
+            }},
+            "/outputs": { "get": {
+                "summary": "Sway's outputs",
+                "responses": { "200": ok("The outputs", array(output)) },
+            }},
+            "/binding": { "get": {
+                "summary": "The last key binding sway ran",
+                "responses": { "200": ok("The binding, if any", binding) },
+            }},
+            "/status": { "get": {
+                "summary": "Whether the daemon is connected to sway",
+                "responses": { "200": ok("The status", json!({
+                    "type": "object",
+                    "required": ["sway"],
+                    "properties": { "sway": status },
+                })) },
+            }},
+            "/cmd": { "post": {
+                "summary": "Run a command and wait for its result",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": request } },
+                },
+                "responses": {
+                    "200": ok("The result of the command, null for most", json!({})),
+                    "404": error("Nothing to focus, or no such window or tab"),
+                    "422": error("A malformed command"),
+                    "500": error("The command failed"),
+                    "503": error("The browser, sway or tmux isn't connected"),
+                    "504": error("No reply in time"),
+                },
+            }},
+            "/events": { "get": {
+                "summary": "Events, named after their msg_type and carrying a DesktopdMessage",
+                "parameters": [
+                    query("topics", "Comma separated, all topics if not given", string.clone()),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The event stream",
+                        "content": { "text/event-stream": { "schema": string.clone() } },
+                    },
+                    "400": error("An unknown topic"),
+                },
+            }},
+            "/schema": { "get": {
+                "summary": "The JSON Schema of the websocket and unix socket protocol",
+                "responses": { "200": ok("The schema", json!({ "type": "object" })) },
+            }},
+            "/openapi.json": { "get": {
+                "summary": "This description",
+                "responses": { "200": ok("The description", json!({ "type": "object" })) },
+            }},
+        },
+        "components": { "schemas": schemas },
+    });
+
+    // every route but the descriptions themselves wants the token, see `http::require_token`
+    for (route, item) in openapi["paths"].as_object_mut().unwrap() {
+        if PUBLIC_ROUTES.contains(&&route[..]) {
+            continue;
+        }
+        for operation in item.as_object_mut().unwrap().values_mut() {
+            let mut parameters = vec![token()];
+            if let Some(Value::Array(others)) = operation.get("parameters") {
+                parameters.extend(others.iter().cloned());
+            }
+            operation["parameters"] = Value::Array(parameters);
+            operation["responses"]["401"] = error("An invalid or missing token");
+        }
+    }
+    openapi
+}
+
+fn ok(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn error(description: &str) -> Value {
+    ok(
+        description,
+        json!({ "$ref": "#/components/schemas/HttpError" }),
+    )
+}
+
+fn token() -> Value {
+    json!({
+        "name": TOKEN_HEADER,
+        "in": "header",
+        "required": true,
+        "schema": { "type": "string" },
+    })
+}
+
+fn query(name: &str, description: &str, schema: Value) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "description": description,
+        "schema": schema,
+    })
+}
+
+fn path(name: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": { "type": "integer" },
+    })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The snapshots in `schema/` are what clients in other languages are generated from, so a
+    /// change to the wire format has to show up there too.
+    fn assert_snapshot(name: &str, snapshot: &str, schema: Value) {
+        let mut json = serde_json::to_string_pretty(&schema).unwrap();
+        json.push('\n');
+        assert!(
+            json == snapshot,
+            "schema/{} is out of date, run `cargo run --bin desktopd-schema` and commit the result",
+            name
+        );
+    }
+
+    #[test]
+    fn protocol_snapshot() {
+        let schema = serde_json::to_value(protocol()).unwrap();
+        assert_snapshot(
+            "protocol.json",
+            include_str!("../schema/protocol.json"),
+            schema,
+        );
+    }
+
+    #[test]
+    fn openapi_snapshot() {
+        assert_snapshot(
+            "openapi.json",
+            include_str!("../schema/openapi.json"),
+            openapi(),
+        );
+    }
+}
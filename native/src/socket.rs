@@ -0,0 +1,159 @@
+use crate::error::DesktopdError;
+use crate::message::DesktopdMessage;
+use crate::state::{GlobalState, PeerAddr, Tx};
+use crate::websocket::{
+    handle_desktopd_message, handle_init_message, rejection, show_notification,
+};
+use async_std::io::BufReader;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::task;
+use futures::channel::mpsc::unbounded;
+use futures::future::Either;
+use futures::prelude::*;
+use futures::{future, pin_mut};
+use log::{error, info};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+/// How long a new peer has to send its `Connect` message.
+const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `$XDG_RUNTIME_DIR/desktopd.sock`, where the daemon listens for local clients.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("desktopd.sock"))
+}
+
+/// Serve the same protocol as the websocket server on a unix socket only we may connect to, with
+/// one JSON encoded `DesktopdMessage` per line.
+pub async fn run(state: GlobalState, sway_tx: Tx) -> Result<(), DesktopdError> {
+    let path = default_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+
+    // a socket nobody answers on is left over from a previous run
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another daemon", path.display()),
+            )
+            .into());
+        }
+        fs::remove_file(&path)?;
+    }
+
+    let listener = bind_private(&path).await?;
+
+    info!("Listening on: {}", path.display());
+
+    let mut peers = 0;
+    while let Ok((stream, _)) = listener.accept().await {
+        peers += 1;
+        let addr = PeerAddr::Unix(peers);
+        task::spawn(accept_connection(
+            state.clone(),
+            sway_tx.clone(),
+            stream,
+            addr,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Bind the socket in a directory only we can enter, and only move it to `path` once nobody
+/// else may connect to it. Binding at `path` and fixing the permissions afterwards would leave a
+/// moment in which anyone could.
+async fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let dir = path.with_extension(format!("sock.{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let bound = dir.join("desktopd.sock");
+    let result = async {
+        let listener = UnixListener::bind(&bound).await?;
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    }
+    .await;
+
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+async fn accept_connection(
+    state: GlobalState,
+    sway_tx: Tx,
+    stream: UnixStream,
+    addr: PeerAddr,
+) -> Result<(), DesktopdError> {
+    let (tx, mut rx) = unbounded();
+    let mut lines = BufReader::new(stream.clone()).lines();
+
+    // a peer that never says hello would hold on to its task forever
+    let first = async_std::future::timeout(INIT_TIMEOUT, lines.next())
+        .await
+        .map_err(|err| {
+            error!("TimeoutError trying to initialize connection: {}", &addr);
+            DesktopdError::TimeoutError(err)
+        })?;
+    let init = match first {
+        Some(line) => serde_json::from_str::<DesktopdMessage>(&line?)?,
+        None => return Err(DesktopdError::ConnectInitError),
+    };
+    if let Err(err) = handle_init_message(state.clone(), &addr, None, tx, init) {
+        if let Some(msg) = rejection(&err) {
+            let mut line = serde_json::to_string(&msg)?;
+            line.push('\n');
+            (&stream).write_all(line.as_bytes()).await?;
+        }
+        return Err(err);
+    }
+
+    info!("New unix socket connection: {}", addr);
+
+    let mut writer = stream;
+    let answer_channel = async move {
+        while let Some(msg) = rx.next().await {
+            let mut line = serde_json::to_string(&msg)?;
+            line.push('\n');
+            writer.write_all(line.as_bytes()).await?;
+        }
+        Ok::<(), DesktopdError>(())
+    };
+
+    let receive_handle = async {
+        while let Some(line) = lines.next().await {
+            let msg = serde_json::from_str::<DesktopdMessage>(&line?)?;
+            handle_desktopd_message(state.clone(), sway_tx.clone(), &addr, msg)?;
+        }
+        Ok::<(), DesktopdError>(())
+    };
+
+    // a peer may hang up right after sending a request, so once we can't write to it any more
+    // still handle whatever it sent
+    pin_mut!(receive_handle, answer_channel);
+    let result = match future::select(receive_handle, answer_channel).await {
+        Either::Left((result, _)) => result,
+        Either::Right((_, receive_handle)) => receive_handle.await,
+    };
+    if let Err(err) = result {
+        error!("Error handling message from {}: {}", addr, err);
+    }
+
+    info!("{} disconnected", &addr);
+    if let Some((conn, _)) = state.lock().unwrap().remove_peer(&addr) {
+        if conn.is_browser() {
+            show_notification("Browser Plugin disconnected");
+        }
+    }
+
+    Ok(())
+}
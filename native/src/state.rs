@@ -1,48 +1,409 @@
+use crate::auth;
 use crate::browser::*;
+use crate::frecency::{unix_now, Frecency, Unsaved};
 use crate::message::*;
+use crate::process;
+use crate::retry::{Retry, RetryPolicy};
 use crate::sway::types::*;
+use crate::tmux::connection::TmuxHandle;
+use crate::tmux::control::Notification;
+use crate::tmux::types::*;
 use async_std::net::SocketAddr;
 use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use std::collections::{HashMap, HashSet};
+use log::{error, info};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub type Tx = UnboundedSender<DesktopdMessage>;
 pub type Rx = UnboundedReceiver<DesktopdMessage>;
 pub type TabId = usize;
 pub type WindowId = usize;
+pub type ConId = usize;
+
+const HISTORY_SIZE: usize = 256;
+/// How long a browser gets to report that it activated a tab we asked it to focus.
+const FOCUS_REQUEST_EXPIRY: Duration = Duration::from_secs(10);
+/// How long to wait for a tagged title to show up in sway. The browser removes the tag again
+/// after a second, so a tag that hasn't been seen by then never will be.
+const TAG_EXPIRY: Duration = Duration::from_secs(3);
+
+/// Windows that never show up tagged, e.g. ones `is_browser` mistakes for the extension's, would
+/// otherwise have their title flicker for as long as they live.
+fn tag_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        initial_delay: TAG_EXPIRY,
+        max_delay: Duration::from_secs(60),
+        deadline: Duration::MAX,
+    }
+}
+
+/// Tags sent for a browser window that isn't mapped to a container yet.
+struct TagAttempts {
+    retry: Retry,
+    /// When the window may be tagged again, `None` once we gave up on it.
+    next: Option<Instant>,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientOrder {
+    /// Windows first, then tabs in window and tab order.
+    #[default]
+    Default,
+    /// Most recently focused first.
+    #[serde(rename = "mru")]
+    Recency,
+    /// Most frequently and recently focused first, across restarts.
+    Frecency,
+}
+
+/// Where a peer is connected from. Websocket peers are known by their address, peers on the unix
+/// socket by the order they connected in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(u64),
+    /// An HTTP request waiting for its reply, or streaming events.
+    Http(u64),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(num) => write!(f, "unix socket peer {}", num),
+            PeerAddr::Http(num) => write!(f, "http request {}", num),
+        }
+    }
+}
+
+/// Who to send the reply to a request to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Requester {
+    pub addr: PeerAddr,
+    pub request_id: Option<u64>,
+}
+
+/// A fix applied to the window list when reconciling it with sway's tree.
+#[derive(Debug)]
+pub enum WindowCorrection {
+    Added(SwayWindow),
+    Removed(SwayWindow),
+    Changed {
+        old: Box<SwayWindow>,
+        new: Box<SwayWindow>,
+    },
+}
+
+/// Whether sway's idea of a window differs, a terminal changing directory isn't drift.
+fn drifted(old: &SwayWindow, new: &SwayWindow) -> bool {
+    let forget_process = |win: &SwayWindow| SwayWindow {
+        process: None,
+        ..win.clone()
+    };
+    forget_process(old) != forget_process(new)
+}
+
+impl fmt::Display for WindowCorrection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use WindowCorrection::*;
+        match self {
+            Added(win) => write!(f, "added missing window {} ({})", win.id, win.name),
+            Removed(win) => write!(f, "removed stale window {} ({})", win.id, win.name),
+            Changed { old, new } => write!(f, "updated window {}: {:?} -> {:?}", new.id, old, new),
+        }
+    }
+}
 
 pub struct State {
-    peers: HashMap<SocketAddr, (ConnectionType, Tx)>,
+    peers: HashMap<PeerAddr, (ConnectionType, Tx)>,
+    capabilities: HashMap<PeerAddr, HashSet<String>>,
+    subscriptions: HashMap<PeerAddr, HashSet<Topic>>,
+    /// The clients subscribers were last told about, see `publish_clients`.
+    published: HashMap<String, DesktopdClient>,
+    /// Set while a `batch` of changes is applied, so clients are published once at its end.
+    batching: bool,
     tabs: HashMap<WindowId, HashMap<TabId, BrowserTab>>,
+    tab_order: HashMap<WindowId, Vec<TabId>>,
+    detached: HashMap<TabId, BrowserTab>,
     windows: HashMap<WindowId, SwayWindow>,
+    browser_windows: HashMap<WindowId, ConId>,
+    /// Nonces sent to the browser, with the window they tag and when they were sent.
+    window_tags: HashMap<String, (WindowId, Instant)>,
+    tag_attempts: HashMap<WindowId, TagAttempts>,
+    focus_requests: HashMap<TabId, (Requester, Instant)>,
+    /// The browser connection each browser window belongs to, so tab commands reach the right one.
+    browser_owners: HashMap<WindowId, PeerAddr>,
+    /// Requests passed on to a browser, by the id they were sent with, see `request_browser`.
+    browser_requests: HashMap<u64, (PeerAddr, Requester)>,
+    next_browser_request: u64,
+    workspaces: Vec<SwayWorkspace>,
+    outputs: Vec<SwayOutput>,
+    last_binding: Option<SwayBinding>,
+    sway_status: SwayStatus,
+    history: VecDeque<ClientRef>,
+    frecency: Frecency,
+    tmux: HashMap<String, (TmuxServer, TmuxHandle)>,
+    token: String,
+    pairing_until: Option<Instant>,
 }
 
 impl State {
     pub fn new() -> State {
         State {
             peers: HashMap::new(),
+            capabilities: HashMap::new(),
+            subscriptions: HashMap::new(),
+            published: HashMap::new(),
+            batching: false,
             tabs: HashMap::new(),
+            tab_order: HashMap::new(),
+            detached: HashMap::new(),
             windows: HashMap::new(),
+            browser_windows: HashMap::new(),
+            window_tags: HashMap::new(),
+            tag_attempts: HashMap::new(),
+            focus_requests: HashMap::new(),
+            browser_owners: HashMap::new(),
+            browser_requests: HashMap::new(),
+            next_browser_request: 0,
+            workspaces: vec![],
+            outputs: vec![],
+            last_binding: None,
+            sway_status: SwayStatus::Disconnected,
+            history: VecDeque::new(),
+            frecency: Frecency::new(),
+            tmux: HashMap::new(),
+            token: String::new(),
+            pairing_until: None,
+        }
+    }
+
+    pub fn with_frecency(frecency: Frecency) -> State {
+        State {
+            frecency,
+            ..State::new()
+        }
+    }
+
+    pub fn unsaved_frecency(&mut self) -> io::Result<Option<Unsaved>> {
+        self.frecency.unsaved()
+    }
+
+    pub fn set_token(&mut self, token: String) {
+        self.token = token;
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn authenticate(&self, token: Option<&str>) -> bool {
+        match token {
+            Some(token) => !self.token.is_empty() && auth::tokens_match(&self.token, token),
+            None => false,
+        }
+    }
+
+    /// Let the next browser that connects without a token in, for a while.
+    pub fn open_pairing(&mut self, duration: Duration) {
+        self.pairing_until = Some(Instant::now() + duration);
+    }
+
+    pub fn pairing_open(&self) -> bool {
+        self.pairing_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Whether a browser may pair now. Pairing closes once it has been used.
+    pub fn take_pairing(&mut self) -> bool {
+        match self.pairing_until.take() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    pub fn add_peer(&mut self, tipe: ConnectionType, addr: PeerAddr, tx: Tx) {
+        if let ConnectionType::Browser { .. } = tipe {
+            // windows we gave up on may be taggable by this one
+            self.tag_attempts.clear();
+
+            // a browser that reconnects replaces its old connection
+            let replaced = self
+                .peers
+                .iter()
+                .filter(|(_, (inner_t, _))| &tipe == inner_t)
+                .map(|(addr, _)| *addr)
+                .collect::<Vec<PeerAddr>>();
+            for addr in replaced {
+                self.remove_peer(&addr);
+            }
         }
+        self.emit_peer(&addr, &tipe, true);
+        self.peers.insert(addr, (tipe, tx));
     }
 
-    pub fn add_peer(&mut self, tipe: ConnectionType, addr: SocketAddr, tx: Tx) {
-        match tipe {
-            ConnectionType::Browser { .. } => {
-                self.peers.retain(|_, (inner_t, _)| &tipe != inner_t);
-                self.peers.insert(addr, (tipe, tx));
+    pub fn remove_peer(&mut self, addr: &PeerAddr) -> Option<(ConnectionType, Tx)> {
+        self.capabilities.remove(addr);
+        self.subscriptions.remove(addr);
+        let removed = self.peers.remove(addr);
+        if let Some((conn, _)) = &removed {
+            self.emit_peer(addr, conn, false);
+        }
+
+        self.browser_owners.retain(|_, owner| owner != addr);
+        let orphaned = self
+            .browser_requests
+            .iter()
+            .filter(|(_, (browser, _))| browser == addr)
+            .map(|(id, _)| *id)
+            .collect::<Vec<u64>>();
+        for id in orphaned {
+            if let Some((_, requester)) = self.browser_requests.remove(&id) {
+                let message = "The browser disconnected".to_owned();
+                self.reply(&requester, Err((ErrorKind::NotConnected, message)));
             }
-            ConnectionType::Cli => {
-                self.peers.insert(addr, (tipe, tx));
+        }
+        removed
+    }
+
+    /// Tell subscribers about a peer coming or going. HTTP requests only register as peers
+    /// until they are answered, so they are left out.
+    fn emit_peer(&self, addr: &PeerAddr, conn: &ConnectionType, connected: bool) {
+        if let PeerAddr::Http(_) = addr {
+            return;
+        }
+        let peer = addr.to_string();
+        let conn = conn.clone();
+        let msg = if connected {
+            DesktopdMessage::PeerConnected { peer, conn }
+        } else {
+            DesktopdMessage::PeerDisconnected { peer, conn }
+        };
+        self.emit(Topic::Peers, msg);
+    }
+
+    /// Replace the topics a peer is subscribed to.
+    pub fn subscribe(&mut self, addr: PeerAddr, topics: Vec<Topic>) {
+        // the snapshot isn't kept up to date while nobody watches the clients
+        if !self.watches_clients() {
+            self.published = self.published_clients();
+        }
+        self.subscriptions
+            .insert(addr, topics.into_iter().collect());
+    }
+
+    fn is_watched(&self, topic: Topic) -> bool {
+        self.subscriptions
+            .values()
+            .any(|topics| topics.contains(&topic))
+    }
+
+    fn watches_clients(&self) -> bool {
+        self.is_watched(Topic::Clients) || self.is_watched(Topic::Tabs)
+    }
+
+    /// Send an event to every peer subscribed to its topic.
+    fn emit(&self, topic: Topic, msg: DesktopdMessage) {
+        for (addr, topics) in self.subscriptions.iter() {
+            if !topics.contains(&topic) {
+                continue;
+            }
+            if let Some((_, tx)) = self.peers.get(addr) {
+                if let Err(err) = tx.unbounded_send(msg.clone()) {
+                    error!("Could not send event to {}: {}", addr, err);
+                }
             }
         }
     }
 
-    pub fn remove_peer(&mut self, addr: &SocketAddr) -> Option<(ConnectionType, Tx)> {
-        self.peers.remove(addr)
+    /// Apply several changes, e.g. a browser's whole tab list, and only compare the clients
+    /// once they are all done.
+    pub fn batch<T>(&mut self, changes: impl FnOnce(&mut State) -> T) -> T {
+        let batching = std::mem::replace(&mut self.batching, true);
+        let result = changes(self);
+        self.batching = batching;
+        self.publish_clients();
+        result
     }
 
-    pub fn find_peer(&self, addr: &SocketAddr) -> Option<Tx> {
+    /// Compare the clients with what subscribers were last told, and send them the difference.
+    /// Called by every mutator that changes a client, once it is done.
+    fn publish_clients(&mut self) {
+        if self.batching || !self.watches_clients() {
+            return;
+        }
+        let current = self.published_clients();
+        let previous = std::mem::replace(&mut self.published, current);
+
+        for (key, client) in previous.iter() {
+            if !self.published.contains_key(key) {
+                let data = client.clone();
+                self.emit(
+                    client_topic(client),
+                    DesktopdMessage::ClientRemoved { data },
+                );
+            }
+        }
+
+        for (key, client) in self.published.iter() {
+            let old = previous.get(key);
+            if old == Some(client) {
+                continue;
+            }
+            let data = client.clone();
+            let msg = match old {
+                None => DesktopdMessage::ClientAdded { data },
+                Some(_) => DesktopdMessage::ClientUpdated { data },
+            };
+            self.emit(client_topic(client), msg);
+        }
+    }
+
+    /// Every window, tab and tmux pane, keyed so they can be told apart between snapshots.
+    fn published_clients(&self) -> HashMap<String, DesktopdClient> {
+        let windows = self.windows.values().map(|win| {
+            let key = format!("win:{}", win.id);
+            (key, DesktopdClient::Window { data: win.clone() })
+        });
+        let tabs = self
+            .tabs
+            .values()
+            .flat_map(|tabs| tabs.values())
+            .map(|tab| {
+                let key = format!("tab:{}", tab.id);
+                (key, DesktopdClient::Tab { data: tab.clone() })
+            });
+        let panes = self
+            .tmux
+            .values()
+            .flat_map(|(server, _)| server.clients())
+            .map(|pane| {
+                let key = format!("tmux:{}:{}", pane.server, pane.pane_id);
+                (key, DesktopdClient::Tmux { data: pane })
+            });
+        windows.chain(tabs).chain(panes).collect()
+    }
+
+    /// Remember the optional features a peer announced when it connected.
+    pub fn set_capabilities(&mut self, addr: PeerAddr, capabilities: Vec<String>) {
+        self.capabilities
+            .insert(addr, capabilities.into_iter().collect());
+    }
+
+    pub fn has_capability(&self, addr: &PeerAddr, capability: &str) -> bool {
+        self.capabilities
+            .get(addr)
+            .is_some_and(|caps| caps.contains(capability))
+    }
+
+    pub fn find_peer(&self, addr: &PeerAddr) -> Option<Tx> {
         if self.peers.contains_key(addr) {
             Some(self.peers[addr].1.clone())
         } else {
@@ -50,6 +411,87 @@ impl State {
         }
     }
 
+    /// Remember which peer asked for a tab to be focused, so it can be told how that went.
+    pub fn request_focus(&mut self, tab_id: TabId, requester: Requester) {
+        self.expire_focus_requests();
+        self.focus_requests
+            .insert(tab_id, (requester, Instant::now()));
+    }
+
+    pub fn take_focus_request(&mut self, tab_id: &TabId) -> Option<Requester> {
+        self.expire_focus_requests();
+        self.focus_requests
+            .remove(tab_id)
+            .map(|(requester, _)| requester)
+    }
+
+    /// Answer the requests for tabs the browser never reported as activated.
+    fn expire_focus_requests(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .focus_requests
+            .iter()
+            .filter(|(_, (_, requested_at))| {
+                now.duration_since(*requested_at) >= FOCUS_REQUEST_EXPIRY
+            })
+            .map(|(tab_id, _)| *tab_id)
+            .collect::<Vec<TabId>>();
+
+        for tab_id in expired {
+            if let Some((requester, _)) = self.focus_requests.remove(&tab_id) {
+                let message = format!("The browser did not activate tab {} in time", tab_id);
+                self.reply(&requester, Err((ErrorKind::Timeout, message)));
+            }
+        }
+    }
+
+    /// Remember which peer a request passed on to a browser came from. The browser is sent the
+    /// returned id instead of the peer's own, as ids are only unique per peer.
+    pub fn request_browser(&mut self, browser: PeerAddr, requester: Requester) -> u64 {
+        self.next_browser_request += 1;
+        let id = self.next_browser_request;
+        self.browser_requests.insert(id, (browser, requester));
+        id
+    }
+
+    /// The peer waiting on a browser's reply, if the reply comes from the browser asked.
+    pub fn take_browser_request(&mut self, browser: &PeerAddr, id: u64) -> Option<Requester> {
+        match self.browser_requests.get(&id) {
+            Some((asked, _)) if asked == browser => self
+                .browser_requests
+                .remove(&id)
+                .map(|(_, requester)| requester),
+            _ => None,
+        }
+    }
+
+    /// Answer a request. A peer that gave no request id isn't waiting for an answer, so it is
+    /// only told about failures.
+    pub fn reply(&self, requester: &Requester, result: RequestResult) {
+        let request_id = requester.request_id;
+        let msg = match result {
+            Ok(_) if request_id.is_none() => return,
+            Ok(result) => DesktopdMessage::Response { request_id, result },
+            Err((kind, message)) => DesktopdMessage::Error {
+                request_id,
+                kind,
+                message,
+            },
+        };
+
+        match self.find_peer(&requester.addr) {
+            Some(peer) => {
+                if let Err(err) = peer.unbounded_send(msg) {
+                    error!("Could not reply to {}: {}", requester.addr, err);
+                }
+            }
+            None => info!(
+                "{} went away before its request was answered",
+                requester.addr
+            ),
+        }
+    }
+
     pub fn get_browser_windows(&self) -> Vec<&SwayWindow> {
         self.windows
             .iter()
@@ -58,7 +500,7 @@ impl State {
             .collect::<Vec<&SwayWindow>>()
     }
 
-    pub fn get_browser_connections(&self) -> Vec<(SocketAddr, Tx)> {
+    pub fn get_browser_connections(&self) -> Vec<(PeerAddr, Tx)> {
         self.peers.iter().fold(vec![], |mut out, (addr, (t, tx))| {
             if let ConnectionType::Browser { .. } = t {
                 out.push((*addr, tx.clone()));
@@ -75,6 +517,8 @@ impl State {
             .collect::<Vec<&SwayWindow>>()
     }
 
+    /// Take out the focused windows, for the caller to put them back with `add_window`. Nothing
+    /// is published until then.
     pub fn remove_focused(&mut self) -> Vec<SwayWindow> {
         let mut out = vec![];
         let ids = self
@@ -93,13 +537,422 @@ impl State {
 
     pub fn add_window(&mut self, win: SwayWindow) {
         self.windows.insert(win.id, win);
+        self.publish_clients();
+    }
+
+    /// Replace all known windows, e.g. after (re)connecting to sway.
+    pub fn set_windows(&mut self, windows: Vec<SwayWindow>) {
+        self.windows.clear();
+        for win in windows {
+            self.windows.insert(win.id, win);
+        }
+        self.publish_clients();
+    }
+
+    /// Bring the window list in line with a complete list of windows fetched from sway, and
+    /// return what had to be fixed.
+    pub fn reconcile_windows(&mut self, windows: Vec<SwayWindow>) -> Vec<WindowCorrection> {
+        let mut corrections = vec![];
+        let fresh = windows
+            .iter()
+            .map(|win| win.id)
+            .collect::<HashSet<WindowId>>();
+
+        let stale = self
+            .windows
+            .keys()
+            .filter(|id| !fresh.contains(id))
+            .copied()
+            .collect::<Vec<WindowId>>();
+
+        for id in stale {
+            if let Some(win) = self.windows.remove(&id) {
+                self.browser_windows.retain(|_, con_id| *con_id != id);
+                corrections.push(WindowCorrection::Removed(win));
+            }
+        }
+
+        for win in windows {
+            match self.windows.get(&win.id) {
+                None => corrections.push(WindowCorrection::Added(win.clone())),
+                Some(old) if drifted(old, &win) => corrections.push(WindowCorrection::Changed {
+                    old: Box::new(old.clone()),
+                    new: Box::new(win.clone()),
+                }),
+                _ => (),
+            }
+            self.windows.insert(win.id, win);
+        }
+
+        self.publish_clients();
+        corrections
+    }
+
+    pub fn window(&self, id: &WindowId) -> Option<&SwayWindow> {
+        self.windows.get(id)
     }
 
     pub fn remove_window(&mut self, id: &WindowId) {
         self.windows.remove(id);
+        self.browser_windows.retain(|_, con_id| con_id != id);
+        self.publish_clients();
+    }
+
+    /// Create a nonce for every browser window that is not yet mapped to a sway container (and
+    /// not already waiting for one). The browser prefixes the window title with it, so we can
+    /// spot the container once sway reports the title change. Windows whose tag expired unseen
+    /// get a new one, a few times, and then not until the browser connects again.
+    pub fn tag_browser_windows(&mut self) -> Vec<(WindowId, String)> {
+        let now = Instant::now();
+        self.window_tags
+            .retain(|_, (_, tagged_at)| now.duration_since(*tagged_at) < TAG_EXPIRY);
+
+        let pending = self
+            .window_tags
+            .values()
+            .map(|(window_id, _)| window_id)
+            .collect::<HashSet<&WindowId>>();
+        let untagged = self
+            .tabs
+            .keys()
+            .filter(|window_id| {
+                self.find_browser_container(window_id).is_none() && !pending.contains(window_id)
+            })
+            .filter(|window_id| match self.tag_attempts.get(window_id) {
+                Some(attempts) => attempts.next.is_some_and(|next| next <= now),
+                None => true,
+            })
+            .copied()
+            .collect::<Vec<WindowId>>();
+
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.subsec_nanos())
+            .unwrap_or(0);
+
+        let mut out = vec![];
+        for window_id in untagged {
+            let nonce = format!("[dsktpd:{:x}:{}]", seed, window_id);
+            self.window_tags.insert(nonce.clone(), (window_id, now));
+
+            let attempts = self
+                .tag_attempts
+                .entry(window_id)
+                .or_insert_with(|| TagAttempts {
+                    retry: Retry::new(tag_policy()),
+                    next: None,
+                });
+            attempts.next = attempts
+                .retry
+                .next_delay()
+                .map(|delay| now + TAG_EXPIRY + delay);
+            if attempts.next.is_none() {
+                info!("Giving up on tagging browser window {}", window_id);
+            }
+            out.push((window_id, nonce));
+        }
+        out
+    }
+
+    /// Check a sway window title for a pending nonce, and if found, record which browser window
+    /// the container belongs to.
+    pub fn resolve_window_tag(&mut self, win: &SwayWindow) -> Option<WindowId> {
+        let nonce = self
+            .window_tags
+            .keys()
+            .find(|nonce| win.name.contains(&nonce[..]))
+            .cloned()?;
+        let (window_id, _) = self.window_tags.remove(&nonce)?;
+        self.tag_attempts.remove(&window_id);
+        self.browser_windows.insert(window_id, win.id);
+        Some(window_id)
+    }
+
+    /// Record that a browser window belongs to the browser connected from `addr`.
+    pub fn set_browser_owner(&mut self, window_id: WindowId, addr: PeerAddr) {
+        self.browser_owners.insert(window_id, addr);
+    }
+
+    /// The browser connection a browser window belongs to, if it is still connected.
+    pub fn browser_owner(&self, window_id: &WindowId) -> Option<PeerAddr> {
+        self.browser_owners
+            .get(window_id)
+            .filter(|addr| self.peers.contains_key(addr))
+            .copied()
+    }
+
+    /// The browser a tab was last focused in, or any connected browser.
+    pub fn last_browser(&self) -> Option<PeerAddr> {
+        self.live_history()
+            .find_map(|client| match client {
+                ClientRef::Tab(tab) => self.browser_owner(&tab.window_id),
+                _ => None,
+            })
+            .or_else(|| {
+                self.get_browser_connections()
+                    .first()
+                    .map(|(addr, _)| *addr)
+            })
+    }
+
+    /// Drop which container shows which browser window, and any tags still waiting for one.
+    pub fn forget_browser_windows(&mut self) {
+        self.browser_windows.clear();
+        self.window_tags.clear();
+    }
+
+    pub fn find_browser_container(&self, window_id: &WindowId) -> Option<ConId> {
+        self.browser_windows
+            .get(window_id)
+            .filter(|con_id| self.windows.contains_key(con_id))
+            .copied()
+    }
+
+    pub fn sway_status(&self) -> SwayStatus {
+        self.sway_status
+    }
+
+    /// Update the sway connection status and let connected command line clients know about it.
+    pub fn set_sway_status(&mut self, status: SwayStatus) {
+        if self.sway_status == status {
+            return;
+        }
+        self.sway_status = status;
+
+        for (conn, tx) in self.peers.values() {
+            if let ConnectionType::Cli = conn {
+                let _ = tx.unbounded_send(DesktopdMessage::SwayStatus { status });
+            }
+        }
+    }
+
+    pub fn set_workspaces(&mut self, workspaces: Vec<SwayWorkspace>) {
+        if self.workspaces == workspaces {
+            return;
+        }
+        self.workspaces = workspaces;
+        let data = self.workspaces.clone();
+        self.emit(Topic::Workspaces, DesktopdMessage::WorkspaceList { data });
+    }
+
+    pub fn workspaces(&self) -> &Vec<SwayWorkspace> {
+        &self.workspaces
+    }
+
+    pub fn focused_workspace(&self) -> Option<&SwayWorkspace> {
+        self.workspaces.iter().find(|ws| ws.focused)
+    }
+
+    pub fn set_outputs(&mut self, outputs: Vec<SwayOutput>) {
+        self.outputs = outputs;
+    }
+
+    pub fn outputs(&self) -> &Vec<SwayOutput> {
+        &self.outputs
+    }
+
+    pub fn set_binding(&mut self, binding: SwayBinding) {
+        self.last_binding = Some(binding);
+    }
+
+    pub fn last_binding(&self) -> Option<&SwayBinding> {
+        self.last_binding.as_ref()
+    }
+
+    /// Start tracking a tmux server a control mode client is attached to.
+    pub fn add_tmux_server(&mut self, name: &str, handle: TmuxHandle) {
+        self.tmux
+            .insert(name.to_owned(), (TmuxServer::new(name), handle));
+    }
+
+    pub fn remove_tmux_server(&mut self, name: &str) {
+        self.tmux.remove(name);
+        self.publish_clients();
+    }
+
+    pub fn tmux(&self, name: &str) -> Option<&TmuxServer> {
+        self.tmux.get(name).map(|(server, _)| server)
+    }
+
+    /// Replace what is known about a tmux server with a fresh listing.
+    pub fn update_tmux_server(&mut self, name: &str, fresh: TmuxServer) {
+        if let Some((server, _)) = self.tmux.get_mut(name) {
+            server.update(fresh);
+            self.publish_clients();
+        }
+    }
+
+    /// Apply a control mode notification to a tmux server. Returns `true` if the server needs to
+    /// be listed again, see `TmuxServer::apply`.
+    pub fn apply_tmux_notification(&mut self, name: &str, notification: &Notification) -> bool {
+        let stale = match self.tmux.get_mut(name) {
+            Some((server, _)) => server.apply(notification),
+            None => return false,
+        };
+        self.publish_clients();
+        stale
+    }
+
+    /// Handle to send commands to a tmux server, while a control mode client is attached to it.
+    pub fn tmux_handle(&self, name: &str) -> Option<TmuxHandle> {
+        self.tmux.get(name).map(|(_, handle)| handle.clone())
+    }
+
+    /// The sway window a client is displayed in. For tabs this is only known once their browser
+    /// window has been mapped.
+    pub fn client_container<'a>(&'a self, client: &'a DesktopdClient) -> Option<&'a SwayWindow> {
+        match client {
+            DesktopdClient::Window { data } => Some(data),
+            DesktopdClient::Tab { data } => self
+                .find_browser_container(&data.window_id)
+                .and_then(|con_id| self.windows.get(&con_id)),
+            DesktopdClient::Tmux { .. } => None,
+        }
+    }
+
+    /// The sway window of process `pid`, or of its closest ancestor that has one. This is how
+    /// the terminal a tmux client runs in is found.
+    pub fn find_process_window(&self, pid: u32) -> Option<ConId> {
+        process::ancestors(pid).into_iter().find_map(|pid| {
+            self.windows
+                .values()
+                .find(|win| win.pid == Some(pid))
+                .map(|win| win.id)
+        })
+    }
+
+    /// Move a client to the top of the focus history.
+    pub fn record_focus(&mut self, client: ClientRef) {
+        // private browsing is supposed to leave no trace, so neither its tabs nor the titles
+        // of its windows end up on disk
+        let key = match &client {
+            ClientRef::Window { id } => self
+                .windows
+                .get(id)
+                .filter(|win| !self.may_be_private(win))
+                .map(window_key),
+            ClientRef::Tab(tab) => self.find_tab(tab).filter(|tab| !tab.incognito).map(tab_key),
+        };
+        if let Some(key) = key {
+            self.frecency.visit(key, unix_now());
+        }
+
+        self.history.retain(|other| other != &client);
+        self.history.push_front(client.clone());
+        self.history.truncate(HISTORY_SIZE);
+        self.emit(Topic::Focus, DesktopdMessage::FocusChanged { data: client });
+    }
+
+    /// Whether the container might show a private browser window. There is no telling until
+    /// the browser window is mapped, which may never happen, so assume it does until then.
+    fn may_be_private(&self, win: &SwayWindow) -> bool {
+        if !win.is_browser() {
+            return false;
+        }
+        let mapped = self
+            .browser_windows
+            .iter()
+            .filter(|(_, con_id)| **con_id == win.id)
+            .map(|(window_id, _)| window_id)
+            .collect::<Vec<&WindowId>>();
+        mapped.is_empty()
+            || mapped
+                .into_iter()
+                .filter_map(|window_id| self.tabs.get(window_id))
+                .any(|tabs| tabs.values().any(|tab| tab.incognito))
+    }
+
+    /// A sway window got focus. If it is a mapped browser window, it is really its active tab
+    /// that the user is looking at.
+    pub fn record_window_focus(&mut self, id: ConId) {
+        let active_tab = self
+            .browser_windows
+            .iter()
+            .find(|(_, con_id)| **con_id == id)
+            .and_then(|(window_id, _)| self.tabs.get(window_id))
+            .and_then(|tabs| tabs.values().find(|tab| tab.active))
+            .map(|tab| BrowserTabRef {
+                tab_id: tab.id,
+                window_id: tab.window_id,
+            });
+
+        match active_tab {
+            Some(tab) => self.record_focus(ClientRef::Tab(tab)),
+            None => self.record_focus(ClientRef::Window { id }),
+        }
+    }
+
+    pub fn forget_client(&mut self, client: &ClientRef) {
+        self.history.retain(|other| other != client);
+    }
+
+    /// The client that had focus before the current one.
+    pub fn previous_client(&self) -> Option<ClientRef> {
+        self.live_history().nth(1).cloned()
+    }
+
+    /// The least recently focused client, so that repeatedly focusing "next" cycles through all
+    /// of them.
+    pub fn next_client(&self) -> Option<ClientRef> {
+        self.live_history().skip(1).last().cloned()
+    }
+
+    fn live_history(&self) -> impl Iterator<Item = &ClientRef> {
+        self.history.iter().filter(move |client| match client {
+            ClientRef::Window { id } => self.windows.contains_key(id),
+            ClientRef::Tab(tab) => self.find_tab(tab).is_some(),
+        })
+    }
+
+    /// The frecency key of a client. Like in the focus history, a mapped browser container
+    /// counts as its active tab.
+    fn client_key(&self, client: &DesktopdClient) -> String {
+        match client {
+            DesktopdClient::Window { data } => self
+                .browser_windows
+                .iter()
+                .find(|(_, con_id)| **con_id == data.id)
+                .and_then(|(window_id, _)| self.tabs.get(window_id))
+                .and_then(|tabs| tabs.values().find(|tab| tab.active))
+                .map(tab_key)
+                .unwrap_or_else(|| window_key(data)),
+            DesktopdClient::Tab { data } => tab_key(data),
+            DesktopdClient::Tmux { data } => pane_key(data),
+        }
+    }
+
+    fn history_rank(&self, client: &DesktopdClient) -> Option<usize> {
+        let (this, tabs_of) = match client {
+            DesktopdClient::Window { data } => {
+                let browser_window = self
+                    .browser_windows
+                    .iter()
+                    .find(|(_, con_id)| **con_id == data.id)
+                    .map(|(window_id, _)| *window_id);
+                (ClientRef::Window { id: data.id }, browser_window)
+            }
+            DesktopdClient::Tab { data } => (
+                ClientRef::Tab(BrowserTabRef {
+                    tab_id: data.id,
+                    window_id: data.window_id,
+                }),
+                None,
+            ),
+            DesktopdClient::Tmux { .. } => return None,
+        };
+
+        // a browser container stands in for its active tab
+        self.history
+            .iter()
+            .position(|other| match (other, tabs_of) {
+                (ClientRef::Tab(tab), Some(window_id)) if tab.window_id == window_id => {
+                    self.find_tab(tab).map(|tab| tab.active).unwrap_or(false)
+                }
+                _ => other == &this,
+            })
     }
 
-    pub fn clients(&self) -> Vec<DesktopdClient> {
+    pub fn clients(&self, order: ClientOrder) -> Vec<DesktopdClient> {
         let window_titles = self
             .windows
             .iter()
@@ -107,10 +960,13 @@ impl State {
             .collect::<HashSet<&str>>();
 
         let tabs = self
-            .tabs
-            .iter()
-            .flat_map(|(_, inner)| inner.iter().map(|(_, tabs)| tabs))
+            .ordered_tabs()
+            .into_iter()
             .filter(|tab| {
+                // the active tab of a browser window is already represented by its container
+                if self.find_browser_container(&tab.window_id).is_some() {
+                    return !tab.active;
+                }
                 window_titles
                     .iter()
                     .fold(true, |result, name| result && !name.contains(&tab.title))
@@ -125,27 +981,206 @@ impl State {
             .collect::<Vec<DesktopdClient>>();
 
         windows.extend(tabs);
+        let mut servers = self.tmux.keys().collect::<Vec<&String>>();
+        servers.sort();
+        windows.extend(
+            servers
+                .into_iter()
+                .flat_map(|name| self.tmux[name].0.clients())
+                .map(|pane| DesktopdClient::Tmux { data: pane }),
+        );
+
+        // both sorts are stable, so clients that were never focused keep their default order
+        match order {
+            ClientOrder::Default => (),
+            ClientOrder::Recency => {
+                windows.sort_by_key(|client| self.history_rank(client).unwrap_or(usize::MAX))
+            }
+            ClientOrder::Frecency => {
+                let now = unix_now();
+                windows.sort_by(|a, b| {
+                    let a = self.frecency.score(&self.client_key(a), now);
+                    let b = self.frecency.score(&self.client_key(b), now);
+                    b.total_cmp(&a)
+                });
+            }
+        }
         windows
     }
 
     pub fn add_tab(&mut self, tab: BrowserTab) {
+        self.insert_tab(tab);
+        self.publish_clients();
+    }
+
+    pub fn remove_tab(&mut self, tab: BrowserTabRef) -> Option<BrowserTab> {
+        let removed = self.take_tab(tab);
+        self.publish_clients();
+        removed
+    }
+
+    fn insert_tab(&mut self, tab: BrowserTab) {
+        // a tab might show up under a different window without us having seen the
+        // detach/attach pair, so make sure it is only ever tracked in one place
+        let stale = self
+            .tabs
+            .iter()
+            .filter(|(window_id, inner)| {
+                **window_id != tab.window_id && inner.contains_key(&tab.id)
+            })
+            .map(|(window_id, _)| *window_id)
+            .collect::<Vec<WindowId>>();
+
+        for window_id in stale {
+            self.take_tab(BrowserTabRef {
+                tab_id: tab.id,
+                window_id,
+            });
+        }
+
+        let window_id = tab.window_id;
+        let order = self.tab_order.entry(window_id).or_default();
+        order.retain(|id| *id != tab.id);
+        order.insert(tab.index.min(order.len()), tab.id);
+
         if self.tabs.contains_key(&tab.window_id) {
             self.tabs.get_mut(&tab.window_id).map(|inner| {
                 inner.insert(tab.id, tab);
             });
         } else {
             let mut map = HashMap::new();
-            let window_id = tab.window_id;
             map.insert(tab.id, tab);
             self.tabs.insert(window_id, map);
         }
+        self.reindex_tabs(&window_id);
     }
 
-    pub fn remove_tab(&mut self, tab: BrowserTabRef) {
+    fn take_tab(&mut self, tab: BrowserTabRef) -> Option<BrowserTab> {
+        // a tab closed while being dragged to another window is only parked in `detached`
+        let parked = self.detached.remove(&tab.tab_id);
+        if let Some(order) = self.tab_order.get_mut(&tab.window_id) {
+            order.retain(|id| *id != tab.tab_id);
+            if order.is_empty() {
+                self.tab_order.remove(&tab.window_id);
+            }
+        }
+
+        let mut removed = None;
         if let Some(mut tabs) = self.tabs.remove(&tab.window_id) {
-            tabs.remove(&tab.tab_id);
-            self.tabs.insert(tab.window_id, tabs);
+            removed = tabs.remove(&tab.tab_id);
+            if tabs.is_empty() {
+                self.browser_windows.remove(&tab.window_id);
+                self.window_tags
+                    .retain(|_, (window_id, _)| *window_id != tab.window_id);
+                self.tag_attempts.remove(&tab.window_id);
+            } else {
+                self.tabs.insert(tab.window_id, tabs);
+            }
+        }
+        self.reindex_tabs(&tab.window_id);
+        removed.or(parked)
+    }
+
+    /// Mark a tab as the active one in its window.
+    pub fn activate_tab(&mut self, tab: &BrowserTabRef) {
+        if let Some(tabs) = self.tabs.get_mut(&tab.window_id) {
+            for (id, inner) in tabs.iter_mut() {
+                inner.active = *id == tab.tab_id;
+            }
+        }
+        self.publish_clients();
+    }
+
+    /// A tab was dragged out of its window. It is parked until the matching `attached` event
+    /// tells us where it went.
+    pub fn detach_tab(&mut self, tab_id: TabId, old_window_id: WindowId) {
+        let tab_ref = BrowserTabRef {
+            tab_id,
+            window_id: old_window_id,
+        };
+        if let Some(tab) = self.take_tab(tab_ref) {
+            self.detached.insert(tab_id, tab);
+        }
+        self.publish_clients();
+    }
+
+    /// Re-key a (previously detached) tab under its new window, at the position the browser
+    /// reported.
+    pub fn attach_tab(&mut self, tab_id: TabId, new_window_id: WindowId, new_position: usize) {
+        let tab = match self.detached.remove(&tab_id) {
+            Some(tab) => Some(tab),
+            None => self.find_tab_by_id(tab_id).cloned().and_then(|tab| {
+                self.take_tab(BrowserTabRef {
+                    tab_id,
+                    window_id: tab.window_id,
+                })
+            }),
+        };
+
+        if let Some(tab) = tab {
+            for client in self.history.iter_mut() {
+                if let ClientRef::Tab(tab_ref) = client {
+                    if tab_ref.tab_id == tab_id {
+                        tab_ref.window_id = new_window_id;
+                    }
+                }
+            }
+            self.insert_tab(BrowserTab {
+                window_id: new_window_id,
+                index: new_position,
+                ..tab
+            });
+        }
+        self.publish_clients();
+    }
+
+    /// Reorder a tab within its window.
+    pub fn move_tab(&mut self, tab_id: TabId, window_id: WindowId, to_index: usize) {
+        if let Some(order) = self.tab_order.get_mut(&window_id) {
+            if let Some(from_index) = order.iter().position(|id| *id == tab_id) {
+                order.remove(from_index);
+                let to_index = to_index.min(order.len());
+                order.insert(to_index, tab_id);
+            }
+        }
+        self.reindex_tabs(&window_id);
+        self.publish_clients();
+    }
+
+    /// The browser swapped out a tab for another one (e.g. when pre-rendering), so the tab keeps
+    /// its place but changes its id.
+    pub fn replace_tab(&mut self, added_tab_id: TabId, removed_tab_id: TabId) {
+        for client in self.history.iter_mut() {
+            if let ClientRef::Tab(tab_ref) = client {
+                if tab_ref.tab_id == removed_tab_id {
+                    tab_ref.tab_id = added_tab_id;
+                }
+            }
         }
+
+        if let Some(tab) = self.find_tab_by_id(removed_tab_id).cloned() {
+            self.take_tab(BrowserTabRef {
+                tab_id: removed_tab_id,
+                window_id: tab.window_id,
+            });
+            self.insert_tab(BrowserTab {
+                id: added_tab_id,
+                ..tab
+            });
+        } else if let Some(tab) = self.detached.remove(&removed_tab_id) {
+            self.detached.insert(
+                added_tab_id,
+                BrowserTab {
+                    id: added_tab_id,
+                    ..tab
+                },
+            );
+        }
+        self.publish_clients();
+    }
+
+    pub fn tab_order(&self, window_id: &WindowId) -> Option<&Vec<TabId>> {
+        self.tab_order.get(window_id)
     }
 
     pub fn find_tab(&self, tab: &BrowserTabRef) -> Option<&BrowserTab> {
@@ -154,6 +1189,339 @@ impl State {
             .map(|tabs| tabs.get(&tab.tab_id))
             .flatten()
     }
+
+    fn find_tab_by_id(&self, tab_id: TabId) -> Option<&BrowserTab> {
+        self.tabs.values().find_map(|inner| inner.get(&tab_id))
+    }
+
+    /// Tabs of all browser windows, in window and tab order.
+    fn ordered_tabs(&self) -> Vec<&BrowserTab> {
+        let mut window_ids = self.tab_order.keys().collect::<Vec<&WindowId>>();
+        window_ids.sort();
+
+        window_ids
+            .into_iter()
+            .flat_map(|window_id| {
+                self.tab_order[window_id]
+                    .iter()
+                    .filter_map(move |tab_id| self.tabs.get(window_id)?.get(tab_id))
+            })
+            .collect::<Vec<&BrowserTab>>()
+    }
+
+    fn reindex_tabs(&mut self, window_id: &WindowId) {
+        if let (Some(order), Some(tabs)) =
+            (self.tab_order.get(window_id), self.tabs.get_mut(window_id))
+        {
+            for (index, tab_id) in order.iter().enumerate() {
+                if let Some(tab) = tabs.get_mut(tab_id) {
+                    tab.index = index;
+                }
+            }
+        }
+    }
 }
 
 pub type GlobalState = Arc<Mutex<State>>;
+
+fn window_key(win: &SwayWindow) -> String {
+    let app = if win.app_id.is_empty() {
+        &win.class
+    } else {
+        &win.app_id
+    };
+    format!("win:{}:{}", app, win.name)
+}
+
+/// Tabs have a topic of their own, so browser-only tools needn't hear about every window.
+fn client_topic(client: &DesktopdClient) -> Topic {
+    match client {
+        DesktopdClient::Tab { .. } => Topic::Tabs,
+        _ => Topic::Clients,
+    }
+}
+
+fn tab_key(tab: &BrowserTab) -> String {
+    format!("tab:{}", tab.url)
+}
+
+fn pane_key(pane: &TmuxClient) -> String {
+    format!(
+        "tmux:{}:{}:{}",
+        pane.server, pane.session, pane.window_index
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tab(id: TabId, window_id: WindowId, index: usize) -> BrowserTab {
+        BrowserTab {
+            id,
+            window_id,
+            title: format!("tab {}", id),
+            url: format!("https://example.org/{}", id),
+            index,
+            active: false,
+            pinned: false,
+            audible: false,
+            incognito: false,
+            fav_icon_url: None,
+        }
+    }
+
+    fn tab_ref(tab_id: TabId, window_id: WindowId) -> BrowserTabRef {
+        BrowserTabRef { tab_id, window_id }
+    }
+
+    /// Window 1 with tabs 10, 11 and 12, window 2 with tab 20.
+    fn state() -> State {
+        let mut state = State::new();
+        for tab in vec![tab(10, 1, 0), tab(11, 1, 1), tab(12, 1, 2), tab(20, 2, 0)] {
+            state.add_tab(tab);
+        }
+        state
+    }
+
+    fn order(state: &State, window_id: WindowId) -> Vec<TabId> {
+        state.tab_order(&window_id).cloned().unwrap_or_default()
+    }
+
+    fn index(state: &State, tab_id: TabId, window_id: WindowId) -> usize {
+        state.find_tab(&tab_ref(tab_id, window_id)).unwrap().index
+    }
+
+    #[test]
+    fn detach_and_attach() {
+        let mut state = state();
+        state.record_focus(ClientRef::Tab(tab_ref(11, 1)));
+
+        state.detach_tab(11, 1);
+        assert_eq!(order(&state, 1), vec![10, 12]);
+        assert_eq!(index(&state, 12, 1), 1);
+        assert!(state.find_tab(&tab_ref(11, 1)).is_none());
+
+        state.attach_tab(11, 2, 0);
+        assert_eq!(order(&state, 2), vec![11, 20]);
+        assert_eq!(index(&state, 11, 2), 0);
+        assert_eq!(index(&state, 20, 2), 1);
+        assert!(state.detached.is_empty());
+        assert_eq!(state.history[0], ClientRef::Tab(tab_ref(11, 2)));
+    }
+
+    #[test]
+    fn attach_without_detach() {
+        let mut state = state();
+        state.attach_tab(10, 2, 5);
+        assert_eq!(order(&state, 1), vec![11, 12]);
+        assert_eq!(order(&state, 2), vec![20, 10]);
+        assert_eq!(index(&state, 10, 2), 1);
+    }
+
+    #[test]
+    fn detach_last_tab_forgets_window() {
+        let mut state = state();
+        state.browser_windows.insert(2, 100);
+        state.detach_tab(20, 2);
+        assert!(state.tab_order(&2).is_none());
+        assert!(state.find_browser_container(&2).is_none());
+
+        state.attach_tab(20, 1, 1);
+        assert_eq!(order(&state, 1), vec![10, 20, 11, 12]);
+    }
+
+    #[test]
+    fn removed_while_detached() {
+        let mut state = state();
+        state.detach_tab(11, 1);
+        assert_eq!(state.remove_tab(tab_ref(11, 1)).map(|tab| tab.id), Some(11));
+        assert!(state.detached.is_empty());
+
+        // the attach that never comes must not bring it back
+        state.attach_tab(11, 2, 0);
+        assert_eq!(order(&state, 2), vec![20]);
+    }
+
+    #[test]
+    fn move_within_window() {
+        let mut state = state();
+        state.move_tab(10, 1, 2);
+        assert_eq!(order(&state, 1), vec![11, 12, 10]);
+        assert_eq!(index(&state, 10, 1), 2);
+        assert_eq!(index(&state, 11, 1), 0);
+
+        state.move_tab(10, 1, 99);
+        assert_eq!(order(&state, 1), vec![11, 12, 10]);
+    }
+
+    #[test]
+    fn replace_keeps_place() {
+        let mut state = state();
+        state.record_focus(ClientRef::Tab(tab_ref(11, 1)));
+        state.replace_tab(13, 11);
+        assert_eq!(order(&state, 1), vec![10, 13, 12]);
+        assert!(state.find_tab(&tab_ref(11, 1)).is_none());
+        assert_eq!(state.history[0], ClientRef::Tab(tab_ref(13, 1)));
+    }
+
+    #[test]
+    fn replace_while_detached() {
+        let mut state = state();
+        state.detach_tab(11, 1);
+        state.replace_tab(13, 11);
+        state.attach_tab(13, 2, 1);
+        assert_eq!(order(&state, 2), vec![20, 13]);
+        assert!(state.detached.is_empty());
+    }
+
+    #[test]
+    fn tab_moving_windows_without_events() {
+        let mut state = state();
+        state.add_tab(tab(10, 2, 0));
+        assert_eq!(order(&state, 1), vec![11, 12]);
+        assert_eq!(order(&state, 2), vec![10, 20]);
+    }
+
+    #[test]
+    fn expired_tags_are_renewed() {
+        let mut state = state();
+        let tags = state.tag_browser_windows();
+        assert_eq!(tags.len(), 2);
+        assert!(state.tag_browser_windows().is_empty());
+
+        let (window_id, nonce) = tags[0].clone();
+        expire(&mut state, window_id);
+        let renewed = state.tag_browser_windows();
+        assert_eq!(renewed.len(), 1);
+        assert_eq!(renewed[0].0, window_id);
+        assert!(!state.window_tags.contains_key(&nonce));
+    }
+
+    /// Pretend the tags of a window went unseen and it is time to try again.
+    fn expire(state: &mut State, window_id: WindowId) {
+        let long_ago = Instant::now() - Duration::from_secs(600);
+        for (tagged, tagged_at) in state.window_tags.values_mut() {
+            if *tagged == window_id {
+                *tagged_at = long_ago;
+            }
+        }
+        if let Some(next) = state
+            .tag_attempts
+            .get_mut(&window_id)
+            .and_then(|attempts| attempts.next.as_mut())
+        {
+            *next = long_ago;
+        }
+    }
+
+    #[test]
+    fn tagging_gives_up_until_browser_reconnects() {
+        let mut state = state();
+        assert_eq!(state.tag_browser_windows().len(), 2);
+        // not before the backoff is over
+        state.window_tags.clear();
+        assert!(state.tag_browser_windows().is_empty());
+
+        for _ in 0..2 {
+            expire(&mut state, 1);
+            let tags = state.tag_browser_windows();
+            assert_eq!(tags.len(), 1);
+            assert_eq!(tags[0].0, 1);
+        }
+        expire(&mut state, 1);
+        assert!(state.tag_browser_windows().is_empty());
+
+        let (tx, _rx) = futures::channel::mpsc::unbounded();
+        let browser = ConnectionType::Browser {
+            id: "firefox".to_owned(),
+        };
+        state.add_peer(browser, PeerAddr::Unix(1), tx);
+        state.window_tags.clear();
+        assert_eq!(state.tag_browser_windows().len(), 2);
+    }
+
+    #[test]
+    fn batches_publish_once() {
+        let mut state = State::new();
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let addr = PeerAddr::Unix(1);
+        state.add_peer(ConnectionType::Cli, addr, tx);
+        state.subscribe(addr, vec![Topic::Tabs]);
+
+        state.batch(|state| {
+            state.add_tab(tab(10, 1, 0));
+            state.add_tab(tab(11, 1, 1));
+            assert!(rx.try_next().is_err());
+        });
+
+        let mut added = vec![];
+        while let Ok(Some(msg)) = rx.try_next() {
+            if let DesktopdMessage::ClientAdded {
+                data: DesktopdClient::Tab { data },
+            } = msg
+            {
+                added.push(data.id);
+            }
+        }
+        added.sort();
+        assert_eq!(added, vec![10, 11]);
+    }
+
+    #[test]
+    fn focus_requests_expire() {
+        let mut state = State::new();
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        let addr = PeerAddr::Unix(1);
+        state.add_peer(ConnectionType::Cli, addr, tx);
+        let requester = Requester {
+            addr,
+            request_id: Some(7),
+        };
+
+        state.request_focus(10, requester);
+        state.focus_requests.get_mut(&10).unwrap().1 -= FOCUS_REQUEST_EXPIRY;
+        assert!(state.take_focus_request(&10).is_none());
+        assert!(matches!(
+            rx.try_next(),
+            Ok(Some(DesktopdMessage::Error {
+                request_id: Some(7),
+                kind: ErrorKind::Timeout,
+                ..
+            }))
+        ));
+    }
+
+    fn window(id: ConId, app_id: &str) -> SwayWindow {
+        SwayWindow {
+            id,
+            app_id: app_id.to_owned(),
+            focused: false,
+            name: format!("window {}", id),
+            output: String::new(),
+            workspace: String::new(),
+            class: String::new(),
+            pid: None,
+            process: None,
+        }
+    }
+
+    #[test]
+    fn unmapped_browser_windows_leave_no_trace() {
+        let mut state = state();
+        state.add_window(window(100, "foot"));
+        state.add_window(window(101, "firefox"));
+        state.record_focus(ClientRef::Window { id: 100 });
+        state.record_focus(ClientRef::Window { id: 101 });
+
+        let now = unix_now();
+        let score = |state: &State, id| state.frecency.score(&window_key(&state.windows[&id]), now);
+        assert!(score(&state, 100) > 0.0);
+        assert_eq!(score(&state, 101), 0.0);
+
+        // once mapped to a window without private tabs, it is fair game
+        state.browser_windows.insert(1, 101);
+        state.record_focus(ClientRef::Window { id: 101 });
+        assert!(score(&state, 101) > 0.0);
+    }
+}
@@ -1,173 +1,561 @@
 use crate::browser::*;
 use crate::message::*;
+use crate::retry::{Clock, Retry, RetryPolicy};
 use crate::state::GlobalState;
-use crate::state::{Rx, Tx};
-use crate::sway::types::SwayWindow;
+use crate::state::{ConId, Requester, Rx, Tx};
+use crate::sway::types::{SwayOutput, SwayStatus, SwayWindow, SwayWorkspace};
+use crate::tmux;
+use crate::websocket;
 use async_i3ipc::{
-    event::{Event, Subscribe, WindowChange, WindowData},
+    event::{
+        Event, ShutdownChange, Subscribe, WindowChange, WindowData, WorkspaceChange, WorkspaceData,
+    },
+    reply::Node,
     I3,
 };
 use async_std::task;
+use futures::future::Either;
 use futures::prelude::*;
 use futures::{future, pin_mut};
-use log::info;
+use log::{error, info, warn};
+use serde_json::Value;
 use std::io;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+const RESYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a tmux server gets to answer when selecting a pane. It is awaited by the command
+/// process, so a hung server would otherwise block every sway command after it.
+const TMUX_TIMEOUT: Duration = Duration::from_secs(2);
 
 // ░█▀█░█░█░█▀▄░█░░░▀█▀░█▀▀
 // ░█▀▀░█░█░█▀▄░█░░░░█░░█░░
 // ░▀░░░▀▀▀░▀▀░░▀▀▀░▀▀▀░▀▀▀
 
+/// How a session with sway came to an end without an error.
+enum Stopped {
+    /// sway exited (or we are shutting down ourselves), so there is nothing to reconnect to.
+    Exit,
+    /// sway is restarting in place and will be back shortly.
+    Restart,
+}
+
+/// Keep a connection to sway alive for as long as the daemon runs. When the connection drops
+/// (e.g. because sway restarted) we reconnect with backoff and rebuild the window list from a
+/// fresh tree. Once the backoff is spent we keep trying every `max_delay`, as the browser, tmux
+/// and the HTTP API are still useful without sway.
 pub async fn run(state: GlobalState, tx: Tx, rx: Rx) -> io::Result<()> {
-    initialize_state(state.clone()).await?;
+    let mut receiver = rx;
+    let mut retry = Retry::new(reconnect_policy());
+    let mut polling = false;
 
-    let listener_state = state.clone();
-    let listener = task::spawn(async move {
-        sway_event_process(listener_state).await;
-    });
+    task::spawn(resync_timer(tx.clone()));
 
-    let commando = task::spawn(async move {
-        sway_command_process(state, tx, rx).await;
-    });
+    loop {
+        let result = sway_session(state.clone(), tx.clone(), &mut receiver, &mut retry).await;
+        state
+            .lock()
+            .unwrap()
+            .set_sway_status(SwayStatus::Disconnected);
 
-    pin_mut!(listener, commando);
-    future::select(listener, commando).await;
+        match result {
+            Ok(Stopped::Exit) => return Ok(()),
+            Ok(Stopped::Restart) => info!("sway is restarting, reconnecting"),
+            Err(err) => error!("Connection to sway failed: {}", err),
+        }
 
-    Ok(())
+        // the session resets the retry once connected
+        if retry.attempts() == 0 {
+            polling = false;
+        }
+        match retry.next_delay() {
+            Some(delay) => wait_disconnected(&state, &mut receiver, delay).await,
+            None => {
+                let delay = reconnect_policy().max_delay;
+                if !polling {
+                    warn!(
+                        "Could not reconnect to sway after {} attempts, trying every {:?}",
+                        retry.attempts(),
+                        delay
+                    );
+                    polling = true;
+                }
+                wait_disconnected(&state, &mut receiver, delay).await
+            }
+        }
+    }
 }
 
-// ░█▀█░█▀▄░▀█▀░█░█░█▀█░▀█▀░█▀▀
-// ░█▀▀░█▀▄░░█░░▀▄▀░█▀█░░█░░█▀▀
-// ░▀░░░▀░▀░▀▀▀░░▀░░▀░▀░░▀░░▀▀▀
+fn reconnect_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 30,
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(5),
+        deadline: Duration::from_secs(60),
+    }
+}
+
+async fn sway_session(
+    state: GlobalState,
+    tx: Tx,
+    rx: &mut Rx,
+    retry: &mut Retry,
+) -> io::Result<Stopped> {
+    let commands = I3::connect().await?;
+    let mut queries = I3::connect().await?;
+    let mut events = I3::connect().await?;
+
+    // subscribe before reading the tree, so nothing happens in between that we don't hear about
+    events
+        .subscribe([
+            Subscribe::Window,
+            Subscribe::Workspace,
+            Subscribe::Output,
+            Subscribe::Binding,
+            Subscribe::Shutdown,
+        ])
+        .await?;
+
+    initialize_state(&mut queries, state.clone()).await?;
 
-async fn initialize_state(state: GlobalState) -> io::Result<()> {
-    let mut i3 = I3::connect().await?;
-    let tree = i3.get_tree().await?;
-    let windows = SwayWindow::collect_windows(&tree);
+    info!("Connected to sway");
+    *retry = Retry::new(reconnect_policy());
+    state.lock().unwrap().set_sway_status(SwayStatus::Connected);
 
-    for win in windows {
-        state.lock().unwrap().add_window(win);
+    // whoever asked for the command being handled when the connection drops is still owed an
+    // answer
+    let mut in_flight = None;
+    let result = {
+        let listener = sway_event_process(state.clone(), events, queries);
+        let commando = sway_command_process(state.clone(), tx, rx, commands, &mut in_flight);
+
+        pin_mut!(listener, commando);
+        match future::select(listener, commando).await {
+            Either::Left((result, _)) => result,
+            Either::Right((result, _)) => result,
+        }
+    };
+    if let Some(requester) = in_flight {
+        let message = "Lost the connection to sway".to_owned();
+        state
+            .lock()
+            .unwrap()
+            .reply(&requester, Err((ErrorKind::NotConnected, message)));
     }
+    result
+}
 
-    Ok(())
+/// Sleep until it is time to reconnect, answering whatever comes in meanwhile. Nothing can be
+/// done without sway, and callers are better off knowing that now than after their timeout.
+async fn wait_disconnected(state: &GlobalState, rx: &mut Rx, delay: Duration) {
+    let until = Instant::now() + delay;
+    loop {
+        let left = until.saturating_duration_since(Instant::now());
+        match async_std::future::timeout(left, rx.next()).await {
+            Ok(Some(msg)) => refuse(state, msg),
+            Ok(None) => return task::sleep(left).await,
+            Err(_) => return,
+        }
+    }
 }
 
-async fn sway_command_process(state: GlobalState, tx: Tx, rx: Rx) {
-    let mut receiver = rx;
-    let mut i3 = I3::connect().await.expect("Failed connecting to sway");
+fn refuse(state: &GlobalState, msg: DesktopdMessage) {
+    let message = "Not connected to sway".to_owned();
+    let mut state = state.lock().unwrap();
+    let requester = match msg {
+        DesktopdMessage::CliRequest {
+            origin: Some(addr),
+            request_id,
+            ..
+        } => Some(Requester { addr, request_id }),
+        DesktopdMessage::BrowserMessage {
+            data: BrowserResponse::Activated(tab),
+        } => state.take_focus_request(&tab.tab_id),
+        _ => None,
+    };
+    if let Some(requester) = requester {
+        state.reply(&requester, Err((ErrorKind::NotConnected, message)));
+    }
+}
 
-    while let Some(msg) = receiver.next().await {
-        handle_incoming_message(&mut i3, state.clone(), tx.clone(), msg).await;
+// ░█▀█░█▀▄░▀█▀░█░█░█▀█░▀█▀░█▀▀
+// ░█▀▀░█▀▄░░█░░▀▄▀░█▀█░░█░░█▀▀
+// ░▀░░░▀░▀░▀▀▀░░▀░░▀░▀░░▀░░▀▀▀
+
+async fn initialize_state(i3: &mut I3, state: GlobalState) -> io::Result<()> {
+    let windows = SwayWindow::fetch_all(i3).await?;
+
+    {
+        // container ids don't survive a restart of sway, so browser windows have to be found
+        // again
+        let mut state = state.lock().unwrap();
+        state.forget_browser_windows();
+        state.set_windows(windows);
+        websocket::tag_browser_windows(&mut state);
     }
+
+    refresh_desktop(i3, state).await
 }
 
-async fn sway_event_process(state: GlobalState) {
-    let mut listener_sway = I3::connect().await.expect("Cannot connect to sway");
+/// Re-read the workspace and output lists. Their events only tell us that something changed,
+/// and a change to one usually affects the other.
+async fn refresh_desktop(i3: &mut I3, state: GlobalState) -> io::Result<()> {
+    let workspaces = SwayWorkspace::fetch_all(i3).await?;
+    let outputs = SwayOutput::fetch_all(i3).await?;
 
-    let _resp = listener_sway
-        .subscribe([Subscribe::Window])
-        .await
-        .expect("Subscription failed");
+    let mut state = state.lock().unwrap();
+    state.set_workspaces(workspaces);
+    state.set_outputs(outputs);
+    Ok(())
+}
 
-    let mut listener = listener_sway.listen();
+/// Handle requests until sway goes away. The requester of the message being handled is kept in
+/// `in_flight`, so the session can answer it if the connection drops in the middle.
+async fn sway_command_process(
+    state: GlobalState,
+    tx: Tx,
+    rx: &mut Rx,
+    mut i3: I3,
+    in_flight: &mut Option<Requester>,
+) -> io::Result<Stopped> {
+    while let Some(msg) = rx.next().await {
+        *in_flight = match msg {
+            DesktopdMessage::CliRequest {
+                origin: Some(addr),
+                request_id,
+                ..
+            } => Some(Requester { addr, request_id }),
+            _ => None,
+        };
+        handle_incoming_message(&mut i3, state.clone(), tx.clone(), msg).await?;
+        *in_flight = None;
+    }
+    Ok(Stopped::Exit)
+}
 
-    let mut sway = I3::connect().await.expect("Cannot connect to sway");
+async fn sway_event_process(state: GlobalState, events: I3, mut sway: I3) -> io::Result<Stopped> {
+    let mut listener = events.listen();
 
     use Event::*;
-    while let Ok(event) = listener.next().await {
-        match event {
+    loop {
+        match listener.next().await? {
             Window(data) => handle_window_event(&mut sway, state.clone(), *data).await,
-            // Workspace(ev) => info!("workspace change event {:?}", ev),
-            // Output(ev) => info!("output event {:?}", ev),
+            Workspace(data) => handle_workspace_event(&mut sway, state.clone(), *data).await,
+            Output(data) => {
+                info!("handling output {} event", data.change);
+                if let Err(err) = refresh_desktop(&mut sway, state.clone()).await {
+                    error!("Could not refresh outputs: {}", err);
+                }
+            }
+            Binding(data) => {
+                info!("binding triggered: {}", data.binding.command);
+                state.lock().unwrap().set_binding(data.into());
+            }
+            Shutdown(data) => {
+                info!("sway is shutting down ({:?})", data.change);
+                return Ok(match data.change {
+                    ShutdownChange::Exit => Stopped::Exit,
+                    ShutdownChange::Restart => Stopped::Restart,
+                });
+            }
             // Mode(ev) => info!("mode event {:?}", ev),
             // BarConfig(ev) => info!("bar config update {:?}", ev),
-            // Binding(ev) => info!("binding event {:?}", ev),
-            // Shutdown(ev) => info!("shutdown event {:?}", ev),
             // Tick(ev) => info!("tick event {:?}", ev),
             _ => (),
         }
     }
 }
 
-async fn handle_incoming_message(i3: &mut I3, state: GlobalState, tx: Tx, msg: DesktopdMessage) {
+async fn handle_incoming_message(
+    i3: &mut I3,
+    state: GlobalState,
+    tx: Tx,
+    msg: DesktopdMessage,
+) -> io::Result<()> {
     use DesktopdMessage::*;
     match msg {
-        CliRequest(req) => handle_cli_request(i3, req).await,
+        CliRequest {
+            request,
+            request_id,
+            origin,
+        } => {
+            let requester = origin.map(|addr| Requester { addr, request_id });
+            handle_cli_request(i3, state, request, requester).await
+        }
         BrowserMessage { data } => {
             handle_browser_response(i3, state.clone(), tx.clone(), data).await
         }
-        _ => (),
+        _ => Ok(()),
     }
 }
 
-async fn handle_cli_request(i3: &mut I3, req: CliRequest) {
-    if let CliRequest::FocusWindow { id } = req {
-        i3.run_command(format!("[con_id={}] focus", id))
-            .await
-            .expect("Error running command");
+async fn handle_cli_request(
+    i3: &mut I3,
+    state: GlobalState,
+    req: CliRequest,
+    requester: Option<Requester>,
+) -> io::Result<()> {
+    use CliRequest::*;
+    let result = match req {
+        FocusWindow { id } => run_command(i3, format!("[con_id={}] focus", id)).await?,
+        Resync => resync(i3, state.clone()).await.map(|_| Ok(Value::Null))?,
+        FocusTmuxPane { server, pane_id } => {
+            focus_tmux_pane(i3, state.clone(), &server, &pane_id).await?
+        }
+        CloseWindow { id } => run_command(i3, format!("[con_id={}] kill", id)).await?,
+        MoveWindowToWorkspace { id, workspace } => {
+            let cmd = format!(
+                "[con_id={}] move container to workspace {}",
+                id,
+                quote(&workspace)
+            );
+            run_command(i3, cmd).await?
+        }
+        MoveWindowToOutput { id, output } => {
+            let cmd = format!(
+                "[con_id={}] move container to output {}",
+                id,
+                quote(&output)
+            );
+            run_command(i3, cmd).await?
+        }
+        ToggleFloating { id } => {
+            run_command(i3, format!("[con_id={}] floating toggle", id)).await?
+        }
+        ToggleFullscreen { id } => {
+            run_command(i3, format!("[con_id={}] fullscreen toggle", id)).await?
+        }
+        SendToScratchpad { id } => {
+            run_command(i3, format!("[con_id={}] move scratchpad", id)).await?
+        }
+        _ => return Ok(()),
+    };
+
+    if let Some(requester) = requester {
+        state.lock().unwrap().reply(&requester, result);
+    }
+    Ok(())
+}
+
+/// Select the pane in tmux, then focus the terminal window showing it.
+async fn focus_tmux_pane(
+    i3: &mut I3,
+    state: GlobalState,
+    server: &str,
+    pane_id: &str,
+) -> io::Result<RequestResult> {
+    let selected = tmux::connection::select_pane(&state, server, pane_id);
+    let pid = match async_std::future::timeout(TMUX_TIMEOUT, selected).await {
+        Ok(Ok(pid)) => pid,
+        Err(_) => {
+            let message = format!(
+                "tmux server {} did not answer within {:?} when selecting pane {}",
+                server, TMUX_TIMEOUT, pane_id
+            );
+            error!("{}", message);
+            return Ok(Err((ErrorKind::Timeout, message)));
+        }
+        Ok(Err(err)) => {
+            let message = format!(
+                "Could not select tmux pane {} on {}: {}",
+                pane_id, server, err
+            );
+            error!("{}", message);
+            return Ok(Err((ErrorKind::CommandFailed, message)));
+        }
+    };
+
+    let window = state.lock().unwrap().find_process_window(pid);
+    match window {
+        Some(id) => run_command(i3, format!("[con_id={}] focus", id)).await,
+        None => {
+            let message = format!("No window found for the terminal of tmux client {}", pid);
+            warn!("{}", message);
+            Ok(Err((ErrorKind::FocusFailed, message)))
+        }
+    }
+}
+
+/// Diff a fresh tree against the windows we know about and fix up whatever drifted.
+async fn resync(i3: &mut I3, state: GlobalState) -> io::Result<()> {
+    let windows = SwayWindow::fetch_all(i3).await?;
+    let corrections = state.lock().unwrap().reconcile_windows(windows);
+
+    for correction in corrections {
+        info!("resync: {}", correction);
+    }
+    Ok(())
+}
+
+/// Periodically ask the command process to resync, until the daemon shuts down.
+async fn resync_timer(tx: Tx) {
+    loop {
+        task::sleep(RESYNC_INTERVAL).await;
+        if tx
+            .unbounded_send(DesktopdMessage::cli_request(CliRequest::Resync))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Quote an argument of a sway command, e.g. a workspace name with spaces in it.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Run a command, logging (rather than failing on) sway's complaints about it. Only errors on
+/// the connection itself are returned as such, the complaints make up the result.
+async fn run_command(i3: &mut I3, cmd: String) -> io::Result<RequestResult> {
+    let mut errors = vec![];
+    for result in i3.run_command(&cmd).await? {
+        if !result.success {
+            let error = result.error.unwrap_or_default();
+            error!("sway could not run `{}`: {}", cmd, error);
+            errors.push(error);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Ok(Value::Null))
+    } else {
+        Ok(Err((ErrorKind::CommandFailed, errors.join("; "))))
     }
 }
 
 /// Handling browser response messages
 ///
 /// When a tab is focused, the browser responds with an activated message. We us this to try and
-/// focus the browser window via sway, since its Window title must now have changed to the tab
-/// title.
+/// focus the browser window via sway. If the browser window has been mapped to its container we
+/// use that, otherwise we fall back to matching the window title, which must now have changed
+/// to the tab title. Since the title change might not have reached us yet, the lookup is retried
+/// for a while before giving up.
 ///
-async fn handle_browser_response(i3: &mut I3, state: GlobalState, tx: Tx, resp: BrowserResponse) {
+async fn handle_browser_response(
+    i3: &mut I3,
+    state: GlobalState,
+    tx: Tx,
+    resp: BrowserResponse,
+) -> io::Result<()> {
     use BrowserResponse::*;
-    use DesktopdMessage::*;
     match resp {
         Activated(tab_ref) => {
-            let browser = {
-                let current = state.lock().unwrap();
-                if let Some(tab) = current.find_tab(&tab_ref) {
-                    current
-                        .get_browser_windows()
-                        .iter()
-                        .filter(|win| {
-                            win.name.contains(&tab.title)
-                                || (tab.url == "about:blank" && win.name == "Mozilla Firefox")
-                        })
-                        .map(|win| *win)
-                        .collect::<Vec<&SwayWindow>>()
-                        .first()
-                        .map(|browser| browser.id)
-                } else {
-                    None
-                }
-            };
+            let browser = find_browser_container(&state, &tab_ref);
             if let Some(id) = browser {
-                i3.run_command(format!("[con_id={}] focus", id))
-                    .await
-                    .expect("Error running command");
+                let requester = state.lock().unwrap().take_focus_request(&tab_ref.tab_id);
+                let result = run_command(i3, format!("[con_id={}] focus", id)).await?;
+                if let Some(requester) = requester {
+                    state.lock().unwrap().reply(&requester, result);
+                }
             } else {
-                let back_channel = tx.clone();
-                let _handle = task::spawn(async move {
-                    let retry = BrowserMessage {
-                        data: BrowserResponse::Activated(tab_ref),
-                    };
-                    task::sleep(Duration::from_millis(1)).await;
-                    back_channel.unbounded_send(retry).expect("Sending failed");
-                });
+                let retry = Retry::new(RetryPolicy::default());
+                task::spawn(retry_focus_tab(state, tx, tab_ref, retry));
             }
+            Ok(())
         }
-        _ => (),
+        _ => Ok(()),
+    }
+}
+
+fn find_browser_container(state: &GlobalState, tab_ref: &BrowserTabRef) -> Option<ConId> {
+    let current = state.lock().unwrap();
+    if let Some(id) = current.find_browser_container(&tab_ref.window_id) {
+        Some(id)
+    } else if let Some(tab) = current.find_tab(tab_ref) {
+        current
+            .get_browser_windows()
+            .iter()
+            .filter(|win| {
+                win.name.contains(&tab.title)
+                    || (tab.url == "about:blank" && win.name == "Mozilla Firefox")
+            })
+            .map(|win| *win)
+            .collect::<Vec<&SwayWindow>>()
+            .first()
+            .map(|browser| browser.id)
+    } else {
+        None
     }
 }
 
-fn mark_focused(state: GlobalState, data: &WindowData) {
+async fn retry_focus_tab<C: Clock>(
+    state: GlobalState,
+    tx: Tx,
+    tab_ref: BrowserTabRef,
+    mut retry: Retry<C>,
+) {
+    while let Some(delay) = retry.next_delay() {
+        task::sleep(delay).await;
+        if let Some(id) = find_browser_container(&state, &tab_ref) {
+            let requester = state.lock().unwrap().take_focus_request(&tab_ref.tab_id);
+            let focus = DesktopdMessage::CliRequest {
+                request: CliRequest::FocusWindow { id },
+                request_id: requester.and_then(|requester| requester.request_id),
+                origin: requester.map(|requester| requester.addr),
+            };
+            if let Err(err) = tx.unbounded_send(focus) {
+                error!("Could not send focus request for container {}: {}", id, err);
+            }
+            return;
+        }
+    }
+
+    warn!(
+        "Giving up on focusing window of tab {} after {} attempts",
+        tab_ref.tab_id,
+        retry.attempts()
+    );
+
     let mut state = state.lock().unwrap();
-    let focused = state.remove_focused();
-    for win in focused {
-        state.add_window(SwayWindow {
-            focused: false,
-            ..win
-        })
+    if let Some(requester) = state.take_focus_request(&tab_ref.tab_id) {
+        let message = format!(
+            "Could not find the window of tab {} in browser window {}",
+            tab_ref.tab_id, tab_ref.window_id
+        );
+        state.reply(&requester, Err((ErrorKind::FocusFailed, message)));
+    }
+}
+
+fn mark_focused(state: GlobalState, windows: Vec<SwayWindow>) {
+    state.lock().unwrap().batch(|state| {
+        let focused = state.remove_focused();
+        for win in focused {
+            state.add_window(SwayWindow {
+                focused: false,
+                ..win
+            })
+        }
+        for win in windows {
+            if win.focused {
+                state.record_window_focus(win.id);
+            }
+            state.add_window(win)
+        }
+    });
+}
+
+/// Containers in window events carry no ancestry nor pid. Windows we already know keep what we
+/// knew about them, only new ones are worth fetching the whole tree for. Anything that moves
+/// windows around is caught by a resync.
+async fn locate_windows(i3: &mut I3, state: &GlobalState, container: &Node) -> Vec<SwayWindow> {
+    let windows = SwayWindow::collect_windows(container);
+    let known = {
+        let state = state.lock().unwrap();
+        windows
+            .iter()
+            .filter_map(|win| state.window(&win.id).cloned())
+            .collect::<Vec<SwayWindow>>()
+    };
+    if known.len() == windows.len() {
+        return SwayWindow::locate(windows, known);
     }
-    let windows = SwayWindow::collect_windows(&data.container);
-    for win in windows {
-        state.add_window(win)
+
+    match SwayWindow::fetch_all(i3).await {
+        Ok(fresh) => SwayWindow::locate(windows, fresh),
+        Err(err) => {
+            error!("Could not fetch tree to locate windows: {}", err);
+            windows
+        }
     }
 }
 
@@ -231,23 +619,295 @@ fn mark_focused(state: GlobalState, data: &WindowData) {
 //     }
 // }
 
-async fn handle_window_event(_i3: &mut I3, state: GlobalState, data: WindowData) {
+async fn handle_workspace_event(i3: &mut I3, state: GlobalState, data: WorkspaceData) {
+    info!("handling workspace {:?} event", data.change);
+    if let Err(err) = refresh_desktop(i3, state.clone()).await {
+        error!("Could not refresh workspaces: {}", err);
+    }
+
+    // moving a workspace to another output moves all its windows along
+    if data.change == WorkspaceChange::Move {
+        if let Err(err) = resync(i3, state).await {
+            error!("Could not resync windows: {}", err);
+        }
+    }
+}
+
+async fn handle_window_event(i3: &mut I3, state: GlobalState, data: WindowData) {
     info!("handleing {:#?} event", data.change);
     match data.change {
         WindowChange::Close => {
             info!("removing window: {:#?}", data.container.id);
-            state.lock().unwrap().remove_window(&data.container.id)
+            let mut state = state.lock().unwrap();
+            state.remove_window(&data.container.id);
+            state.forget_client(&ClientRef::Window {
+                id: data.container.id,
+            });
         }
 
         WindowChange::Focus => {
-            mark_focused(state.clone(), &data);
+            let windows = locate_windows(i3, &state, &data.container).await;
+            mark_focused(state.clone(), windows);
             // maybe_split_container(i3, &data).await;
         }
 
         _ => {
-            for win in SwayWindow::collect_windows(&data.container) {
-                state.lock().unwrap().add_window(win)
+            let windows = locate_windows(i3, &state, &data.container).await;
+            state.lock().unwrap().batch(|state| {
+                for win in windows {
+                    if win.is_browser() {
+                        if let Some(window_id) = state.resolve_window_tag(&win) {
+                            info!(
+                                "mapped browser window {} to container {}",
+                                window_id, win.id
+                            );
+                        }
+                    }
+                    state.add_window(win)
+                }
+            });
+        }
+    }
+
+    // a tag may have gone unseen, e.g. while sway was reconnecting, and windows that were
+    // tagged before their container existed still need one
+    websocket::tag_browser_windows(&mut state.lock().unwrap());
+
+    // windows moving to and from the scratchpad or between workspaces are where the incremental
+    // updates above tend to go wrong
+    if data.change == WindowChange::Move || data.change == WindowChange::Floating {
+        if let Err(err) = resync(i3, state).await {
+            error!("Could not resync windows: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{PeerAddr, State};
+    use async_std::os::unix::net::{UnixListener, UnixStream};
+    use futures::channel::mpsc::unbounded;
+    use serde_json::json;
+    use std::net::Shutdown;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::{env, fs};
+
+    /// Just enough of sway's IPC for a session: replies to the queries `sway_session` makes, and
+    /// connections that can all be dropped at once, like when sway restarts.
+    struct FakeSway {
+        path: PathBuf,
+        streams: Arc<Mutex<Vec<UnixStream>>>,
+        accept: task::JoinHandle<()>,
+    }
+
+    impl FakeSway {
+        async fn start(path: &Path, windows: &[(usize, &str)]) -> FakeSway {
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path).await.unwrap();
+            let tree = tree(windows);
+            let streams = Arc::new(Mutex::new(vec![]));
+            let accepted = streams.clone();
+            let accept = task::spawn(async move {
+                let mut incoming = listener.incoming();
+                while let Some(Ok(stream)) = incoming.next().await {
+                    accepted.lock().unwrap().push(stream.clone());
+                    task::spawn(serve(stream, tree.clone()));
+                }
+            });
+            FakeSway {
+                path: path.to_owned(),
+                streams,
+                accept,
             }
         }
+
+        async fn stop(self) {
+            self.accept.cancel().await;
+            for stream in self.streams.lock().unwrap().iter() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    async fn serve(mut stream: UnixStream, tree: Value) {
+        let mut header = [0u8; 14];
+        while stream.read_exact(&mut header).await.is_ok() {
+            let len = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+            let msg_type = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+            let mut payload = vec![0u8; len as usize];
+            if stream.read_exact(&mut payload).await.is_err() {
+                return;
+            }
+
+            let reply = match msg_type {
+                // RUN_COMMAND, GET_WORKSPACES, SUBSCRIBE, GET_OUTPUTS, GET_TREE
+                0 => json!([{ "success": true }]),
+                1 => json!([{
+                    "id": 101, "num": 1, "name": "1", "output": "eDP-1",
+                    "focused": true, "visible": true, "urgent": false,
+                }]),
+                2 => json!({ "success": true }),
+                3 => json!([{
+                    "name": "eDP-1", "active": true, "focused": true,
+                    "current_workspace": "1", "current_mode": null,
+                }]),
+                4 => tree.clone(),
+                _ => json!({ "success": false }),
+            };
+
+            let body = reply.to_string();
+            let mut msg = b"i3-ipc".to_vec();
+            msg.extend(&(body.len() as u32).to_ne_bytes());
+            msg.extend(&msg_type.to_ne_bytes());
+            msg.extend(body.as_bytes());
+            if stream.write_all(&msg).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// A tree with a single output and workspace holding a window per `(con_id, app_id)`.
+    fn tree(windows: &[(usize, &str)]) -> Value {
+        let windows = windows
+            .iter()
+            .map(|(id, app_id)| {
+                let mut con = node(*id, "con", app_id, vec![]);
+                con["app_id"] = json!(app_id);
+                con["pid"] = json!(1000 + id);
+                con
+            })
+            .collect();
+        let workspace = node(101, "workspace", "1", windows);
+        let output = node(100, "output", "eDP-1", vec![workspace]);
+        node(1_000_000, "root", "root", vec![output])
+    }
+
+    fn node(id: usize, node_type: &str, name: &str, nodes: Vec<Value>) -> Value {
+        let rect = json!({ "x": 0, "y": 0, "width": 1920, "height": 1080 });
+        json!({
+            "id": id, "name": name, "num": null, "type": node_type, "layout": "splith",
+            "output": null, "orientation": "horizontal", "border": "normal", "percent": null,
+            "rect": rect, "window_rect": rect, "deco_rect": rect, "geometry": rect,
+            "window": null, "window_properties": null, "window_type": null,
+            "current_border_width": 0, "urgent": false, "marks": null, "focused": false,
+            "focus": [], "sticky": false, "floating": null, "floating_nodes": [],
+            "fullscreen_mode": 0, "nodes": nodes, "app_id": null,
+        })
+    }
+
+    async fn wait_for(state: &GlobalState, done: impl Fn(&State) -> bool) {
+        for _ in 0..250 {
+            if done(&state.lock().unwrap()) {
+                return;
+            }
+            task::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Timed out waiting for sway");
+    }
+
+    fn connected_with(window: ConId) -> impl Fn(&State) -> bool {
+        move |state| state.sway_status() == SwayStatus::Connected && state.window(&window).is_some()
+    }
+
+    fn browser_tab(window_id: usize) -> BrowserTab {
+        BrowserTab {
+            id: 70,
+            window_id,
+            title: "desktopd".to_owned(),
+            url: "https://example.org".to_owned(),
+            index: 0,
+            active: true,
+            pinned: false,
+            audible: false,
+            incognito: false,
+            fav_icon_url: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn reconnects_after_restart() {
+        let path = env::temp_dir().join(format!("desktopd-test-sway-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        env::set_var("I3SOCK", &path);
+
+        let state = GlobalState::new(Mutex::new(State::new()));
+        let (tx, rx) = unbounded();
+        task::spawn(run(state.clone(), tx, rx));
+
+        // sway isn't there yet, which is no reason to give up
+        task::sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            state.lock().unwrap().sway_status(),
+            SwayStatus::Disconnected
+        );
+
+        let sway = FakeSway::start(&path, &[(1, "firefox"), (2, "foot")]).await;
+        wait_for(&state, connected_with(1)).await;
+        {
+            let mut state = state.lock().unwrap();
+            assert_eq!(state.window(&2).and_then(|win| win.pid), Some(1002));
+            assert_eq!(state.workspaces()[0].name, "1");
+
+            // map browser window 7 to container 1, like sway reporting the tagged title would
+            state.add_tab(browser_tab(7));
+            let (window_id, nonce) = state.tag_browser_windows().pop().unwrap();
+            assert_eq!(window_id, 7);
+            let mut win = state.window(&1).cloned().unwrap();
+            win.name = format!("{} Mozilla Firefox", nonce);
+            state.resolve_window_tag(&win);
+            assert_eq!(state.find_browser_container(&7), Some(1));
+        }
+
+        sway.stop().await;
+        wait_for(&state, |state| {
+            state.sway_status() == SwayStatus::Disconnected
+        })
+        .await;
+
+        // sway comes back with new container ids
+        let sway = FakeSway::start(&path, &[(11, "firefox"), (12, "foot")]).await;
+        wait_for(&state, connected_with(11)).await;
+        {
+            let mut state = state.lock().unwrap();
+            assert!(state.window(&1).is_none());
+            assert!(state.find_browser_container(&7).is_none());
+            // already tagged again, so it isn't offered a second time
+            assert!(state.tag_browser_windows().is_empty());
+        }
+        sway.stop().await;
+    }
+
+    #[async_std::test]
+    async fn refuses_requests_while_disconnected() {
+        let state = GlobalState::new(Mutex::new(State::new()));
+        let (peer_tx, mut peer_rx) = unbounded();
+        let addr = PeerAddr::Unix(1);
+        state
+            .lock()
+            .unwrap()
+            .add_peer(ConnectionType::Cli, addr, peer_tx);
+
+        let (tx, mut rx) = unbounded();
+        tx.unbounded_send(DesktopdMessage::CliRequest {
+            request: CliRequest::FocusWindow { id: 1 },
+            request_id: Some(3),
+            origin: Some(addr),
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        wait_disconnected(&state, &mut rx, Duration::from_millis(200)).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(matches!(
+            peer_rx.try_next(),
+            Ok(Some(DesktopdMessage::Error {
+                request_id: Some(3),
+                kind: ErrorKind::NotConnected,
+                ..
+            }))
+        ));
     }
 }
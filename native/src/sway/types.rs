@@ -1,11 +1,17 @@
+use crate::process::{ProcessInfo, ProcessTree};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 use async_i3ipc::{
+    event::BindingData,
+    msg::Msg,
     reply::{Node, NodeType},
     I3,
 };
+use std::io;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct SwayWindow {
     pub id: usize,
     pub app_id: String,
@@ -14,6 +20,11 @@ pub struct SwayWindow {
     pub output: String,
     pub workspace: String,
     pub class: String,
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// The foreground process of the window, as of the last time the tree was fetched.
+    #[serde(default)]
+    pub process: Option<ProcessInfo>,
 }
 
 impl SwayWindow {
@@ -22,9 +33,30 @@ impl SwayWindow {
         normalized.contains("firefox") || normalized.contains("chrome")
     }
 
+    /// Fill in what the window's foreground process is running, e.g. the command and cwd of the
+    /// shell in a terminal.
+    pub fn with_process(self, processes: &ProcessTree) -> SwayWindow {
+        let process = self
+            .pid
+            .and_then(|pid| ProcessInfo::read(processes.foreground(pid)));
+        SwayWindow { process, ..self }
+    }
+
     pub fn collect_windows(node: &Node) -> Vec<SwayWindow> {
+        SwayWindow::collect_windows_in(node, "", "")
+    }
+
+    /// Walk the tree below `node`, carrying the names of the enclosing output and workspace
+    /// down to each container.
+    fn collect_windows_in(node: &Node, output: &str, workspace: &str) -> Vec<SwayWindow> {
+        let empty = String::from("");
+        let (output, workspace) = match node.node_type {
+            NodeType::Output => (node.name.as_ref().unwrap_or(&empty).as_str(), ""),
+            NodeType::Workspace => (output, node.name.as_ref().unwrap_or(&empty).as_str()),
+            _ => (output, workspace),
+        };
+
         let mut this = if node.node_type == NodeType::Con && node.name.is_some() {
-            let empty = String::from("");
             let name = node.name.as_ref().unwrap_or(&empty);
             let app_id = node.app_id.as_ref().unwrap_or(&empty);
             let class = node
@@ -37,10 +69,12 @@ impl SwayWindow {
                 id: node.id,
                 focused: node.focused,
                 app_id: app_id.to_owned(),
-                output: empty.to_owned(),
-                workspace: empty.to_owned(),
+                output: output.to_owned(),
+                workspace: workspace.to_owned(),
                 class: class.unwrap_or(empty.to_owned()),
                 name: name.to_owned(),
+                pid: None,
+                process: None,
             };
 
             vec![win]
@@ -48,13 +82,13 @@ impl SwayWindow {
             vec![]
         };
         let siblings = node.nodes.iter().fold(vec![], |mut vec, child| {
-            let children = SwayWindow::collect_windows(child);
+            let children = SwayWindow::collect_windows_in(child, output, workspace);
             vec.extend(children);
             vec
         });
 
         let floating_siblings = node.floating_nodes.iter().fold(vec![], |mut vec, child| {
-            let children = SwayWindow::collect_windows(child);
+            let children = SwayWindow::collect_windows_in(child, output, workspace);
             vec.extend(children);
             vec
         });
@@ -64,15 +98,139 @@ impl SwayWindow {
         this
     }
 
-    pub async fn fetch() -> Vec<SwayWindow> {
-        // establish a connection to i3 over a unix socket
-        let mut connection = I3::connect().await.expect("Connection to Sway failed");
+    /// Containers in window events carry no ancestry (nor pid), so look them up in a window
+    /// list that has them, either freshly fetched or the one we already know.
+    pub fn locate(windows: Vec<SwayWindow>, fresh: Vec<SwayWindow>) -> Vec<SwayWindow> {
+        let located = fresh
+            .into_iter()
+            .map(|win| (win.id, win))
+            .collect::<HashMap<usize, SwayWindow>>();
+
+        windows
+            .into_iter()
+            .map(|win| match located.get(&win.id) {
+                Some(found) => SwayWindow {
+                    output: found.output.to_owned(),
+                    workspace: found.workspace.to_owned(),
+                    pid: found.pid,
+                    process: found.process.clone(),
+                    ..win
+                },
+                None => win,
+            })
+            .collect()
+    }
+
+    /// All windows in the tree, with their processes. `Node` doesn't know about sway's `pid`
+    /// field, so the tree is read raw and the pids are picked out before parsing it.
+    ///
+    /// Reading the processes scans `/proc`, which is why it happens here rather than while the
+    /// windows are listed from `State`.
+    pub async fn fetch_all(i3: &mut I3) -> io::Result<Vec<SwayWindow>> {
+        i3.send_msg(Msg::Tree).await?;
+        let raw = i3.read_msg::<serde_json::Value>().await?.body;
+
+        let mut pids = HashMap::new();
+        collect_pids(&raw, &mut pids);
+
+        let tree: Node = serde_json::from_value(raw)?;
+        let processes = ProcessTree::read();
+        Ok(SwayWindow::collect_windows(&tree)
+            .into_iter()
+            .map(|win| {
+                SwayWindow {
+                    pid: pids.get(&win.id).copied(),
+                    ..win
+                }
+                .with_process(&processes)
+            })
+            .collect())
+    }
+}
+
+fn collect_pids(node: &serde_json::Value, pids: &mut HashMap<usize, u32>) {
+    if let (Some(id), Some(pid)) = (node["id"].as_u64(), node["pid"].as_u64()) {
+        pids.insert(id as usize, pid as u32);
+    }
+    for key in &["nodes", "floating_nodes"] {
+        if let Some(children) = node[key].as_array() {
+            for child in children {
+                collect_pids(child, pids);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SwayStatus {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct SwayWorkspace {
+    pub id: usize,
+    pub num: i32,
+    pub name: String,
+    pub output: String,
+    pub focused: bool,
+    pub visible: bool,
+    pub urgent: bool,
+}
+
+impl SwayWorkspace {
+    pub async fn fetch_all(i3: &mut I3) -> io::Result<Vec<SwayWorkspace>> {
+        i3.send_msg(Msg::Workspaces).await?;
+        Ok(i3.read_msg::<Vec<SwayWorkspace>>().await?.body)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct OutputMode {
+    pub width: i32,
+    pub height: i32,
+    pub refresh: i32,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct SwayOutput {
+    pub name: String,
+    #[serde(default)]
+    pub make: String,
+    #[serde(default)]
+    pub model: String,
+    pub active: bool,
+    #[serde(default)]
+    pub focused: bool,
+    pub current_workspace: Option<String>,
+    #[serde(default)]
+    pub modes: Vec<OutputMode>,
+    pub current_mode: Option<OutputMode>,
+}
 
-        let tree = connection
-            .get_tree()
-            .await
-            .expect("Unable to fetch tree from sway");
+impl SwayOutput {
+    /// Sway reports more about outputs than the i3 reply type knows about (e.g. their modes), so
+    /// the reply is read into our own type.
+    pub async fn fetch_all(i3: &mut I3) -> io::Result<Vec<SwayOutput>> {
+        i3.send_msg(Msg::Outputs).await?;
+        Ok(i3.read_msg::<Vec<SwayOutput>>().await?.body)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct SwayBinding {
+    pub command: String,
+    pub symbol: Option<String>,
+    pub modifiers: Vec<String>,
+}
 
-        SwayWindow::collect_windows(&tree)
+impl From<BindingData> for SwayBinding {
+    fn from(data: BindingData) -> SwayBinding {
+        SwayBinding {
+            command: data.binding.command,
+            symbol: data.binding.symbol,
+            modifiers: data.binding.event_state_mask,
+        }
     }
 }
@@ -1,30 +1,362 @@
+use crate::error::DesktopdError;
 use crate::state::*;
+use crate::tmux::control::{ControlEvent, Notification, Parser};
+use crate::tmux::types::*;
 use async_process::{Command, Stdio};
+use async_std::task;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::channel::oneshot;
+use futures::future::Either;
 use futures::prelude::*;
-use futures::{future, io::BufReader};
-use log::info;
+use futures::{future, io::BufReader, pin_mut};
+use log::{debug, error, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
 use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub async fn run(_state: GlobalState) -> io::Result<()> {
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+/// How often panes are listed again. Nothing is notified when a pane starts another command or
+/// changes directory, and layout changes are only reported for the attached session.
+const PANE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const SOCKETS_VAR: &str = "DESKTOPD_TMUX_SOCKETS";
+
+/// The lines tmux replied with, or the error lines if the command failed.
+pub type Reply = Result<Vec<String>, Vec<String>>;
+
+type Pending = Arc<Mutex<VecDeque<oneshot::Sender<Reply>>>>;
+
+/// Sends commands to a tmux control mode client.
+#[derive(Debug, Clone)]
+pub struct TmuxHandle {
+    tx: UnboundedSender<(String, oneshot::Sender<Reply>)>,
+}
+
+impl TmuxHandle {
+    /// Whether the control mode client this handle sends to has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Run a tmux command and wait for its output.
+    pub async fn command(&self, cmd: &str) -> Result<Vec<String>, DesktopdError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx
+            .unbounded_send((cmd.to_owned(), reply_tx))
+            .map_err(|_| DesktopdError::TmuxCommandError("tmux is not connected".to_owned()))?;
+
+        match reply_rx.await {
+            Ok(Ok(lines)) => Ok(lines),
+            Ok(Err(lines)) => Err(DesktopdError::TmuxCommandError(lines.join("\n"))),
+            Err(_) => Err(DesktopdError::TmuxCommandError(
+                "tmux exited before replying".to_owned(),
+            )),
+        }
+    }
+}
+
+/// A terminal attached to the tmux server.
+struct AttachedClient {
+    pid: u32,
+    tty: String,
+    session_id: String,
+}
+
+/// A tmux server we can attach to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TmuxSocket {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl TmuxSocket {
+    /// The servers to attach to: the sockets listed in `DESKTOPD_TMUX_SOCKETS` (separated by
+    /// `:`), or else every socket in tmux's socket directory, i.e. the default server and those
+    /// started with `-L`. Sockets are named after their file, unless sockets in different
+    /// directories share a file name, then they go by their full path.
+    pub fn discover() -> Vec<TmuxSocket> {
+        let paths = match env::var_os(SOCKETS_VAR) {
+            Some(paths) => env::split_paths(&paths).collect::<Vec<PathBuf>>(),
+            None => socket_dir()
+                .and_then(|dir| fs::read_dir(dir).ok())
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| Some(entry.ok()?.path()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+
+        let sockets = paths
+            .into_iter()
+            .filter(|path| {
+                fs::metadata(path)
+                    .map(|meta| meta.file_type().is_socket())
+                    .unwrap_or(false)
+            })
+            .filter_map(|path| {
+                let name = path.file_name()?.to_string_lossy().into_owned();
+                Some(TmuxSocket { name, path })
+            })
+            .collect::<Vec<TmuxSocket>>();
+
+        let mut names = HashMap::<String, usize>::new();
+        for socket in sockets.iter() {
+            *names.entry(socket.name.to_owned()).or_default() += 1;
+        }
+        sockets
+            .into_iter()
+            .map(|socket| match names[&socket.name] {
+                1 => socket,
+                _ => TmuxSocket {
+                    name: socket.path.to_string_lossy().into_owned(),
+                    ..socket
+                },
+            })
+            .collect()
+    }
+}
+
+/// Where tmux puts its sockets, `$TMUX_TMPDIR/tmux-UID` (see tmux(1) `-L`).
+fn socket_dir() -> Option<PathBuf> {
+    let uid = fs::metadata("/proc/self").ok()?.uid();
+    let tmpdir = env::var_os("TMUX_TMPDIR")
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "/tmp".into());
+    Some(PathBuf::from(tmpdir).join(format!("tmux-{}", uid)))
+}
+
+/// Show a pane in one of the terminals attached to tmux, preferring one that is already on the
+/// pane's session. Returns the pid of the tmux client in that terminal.
+pub async fn select_pane(
+    state: &GlobalState,
+    server: &str,
+    pane_id: &str,
+) -> Result<u32, DesktopdError> {
+    let (handle, window_id, session_id) = {
+        let state = state.lock().unwrap();
+        let not_connected =
+            || DesktopdError::TmuxCommandError(format!("tmux server {} is not connected", server));
+        let handle = state.tmux_handle(server).ok_or_else(not_connected)?;
+        let tmux = state.tmux(server).ok_or_else(not_connected)?;
+        let window = tmux
+            .find_pane(pane_id)
+            .and_then(|pane| tmux.windows.get(&pane.window_id))
+            .ok_or_else(|| DesktopdError::TmuxCommandError(format!("unknown pane {}", pane_id)))?;
+        (handle, window.id.to_owned(), window.session_id.to_owned())
+    };
+
+    let clients = handle
+        .command(
+            "list-clients -F '#{client_pid}\t#{client_tty}\t#{session_id}\t#{client_control_mode}'",
+        )
+        .await?
+        .iter()
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            match fields.as_slice() {
+                [pid, tty, session_id, control_mode] if *control_mode != "1" => {
+                    Some(AttachedClient {
+                        pid: pid.parse().ok()?,
+                        tty: tty.to_string(),
+                        session_id: session_id.to_string(),
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect::<Vec<AttachedClient>>();
+
+    let client = clients
+        .iter()
+        .find(|client| client.session_id == session_id)
+        .or_else(|| clients.first())
+        .ok_or_else(|| {
+            DesktopdError::TmuxCommandError("no terminal is attached to tmux".to_owned())
+        })?;
+
+    // targets are quoted, as tmux would expand the `$` of session ids
+    if client.session_id != session_id {
+        let cmd = format!("switch-client -c '{}' -t '{}'", client.tty, session_id);
+        handle.command(&cmd).await?;
+    }
+    handle
+        .command(&format!("select-window -t '{}'", window_id))
+        .await?;
+    handle
+        .command(&format!("select-pane -t '{}'", pane_id))
+        .await?;
+
+    Ok(client.pid)
+}
+
+/// Keep a control mode client attached to every tmux server that can be discovered. Servers are
+/// looked for periodically, so ones that are started later (or restarted) are picked up too.
+pub async fn run(state: GlobalState) {
+    // by path, as a server's name changes when another one with the same file name shows up
+    let attached = Arc::new(Mutex::new(HashSet::<PathBuf>::new()));
+
+    loop {
+        for socket in TmuxSocket::discover() {
+            if !attached.lock().unwrap().insert(socket.path.to_owned()) {
+                continue;
+            }
+
+            let state = state.clone();
+            let attached = attached.clone();
+            task::spawn(async move {
+                match run_server(state, &socket).await {
+                    Ok(()) => info!("tmux server {} went away", socket.name),
+                    Err(err) => debug!("Could not attach to tmux server {}: {}", socket.name, err),
+                }
+                attached.lock().unwrap().remove(&socket.path);
+            });
+        }
+
+        task::sleep(DISCOVERY_INTERVAL).await;
+    }
+}
+
+/// Attach to a tmux server in control mode and keep `State`'s model of its sessions, windows
+/// and panes up to date until it exits.
+async fn run_server(state: GlobalState, socket: &TmuxSocket) -> io::Result<()> {
     let mut tmux = Command::new("tmux")
+        .arg("-S")
+        .arg(&socket.path)
         .arg("-C")
         .arg("attach")
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(Stdio::null())
         .spawn()?;
 
-    // TODO: wire it up so that we can send comands to tmux
-    //
-    // let (tx, rx) = unbounded::<String>();
-    // let input = BufWriter::new(tmux.stdin.take().unwrap());
+    let mut input = tmux.stdin.take().unwrap();
     let lines = BufReader::new(tmux.stdout.take().unwrap()).lines();
 
-    let handle = lines.try_for_each(|line: String| {
-        if !line.starts_with("%output") {
-            info!("tmux: {}", line);
+    let (tx, mut rx) = unbounded::<(String, oneshot::Sender<Reply>)>();
+    let handle = TmuxHandle { tx };
+    let pending = Pending::default();
+    let name = socket.name.to_owned();
+
+    state.lock().unwrap().add_tmux_server(&name, handle.clone());
+
+    // tmux answers commands in order, so replies are matched to senders first in, first out
+    let writer_pending = pending.clone();
+    let writer = async move {
+        while let Some((cmd, reply_tx)) = rx.next().await {
+            debug!("tmux {}: command {}", name, cmd);
+            writer_pending.lock().unwrap().push_back(reply_tx);
+            input.write_all(format!("{}\n", cmd).as_bytes()).await?;
+            input.flush().await?;
+        }
+        Ok(())
+    };
+
+    let mut parser = Parser::new();
+    let reader_state = state.clone();
+    let reader_handle = handle.clone();
+    let name = socket.name.to_owned();
+    let reader = lines.try_for_each(move |line: String| {
+        match parser.feed(&line) {
+            Some(ControlEvent::Reply {
+                from_client: true,
+                success,
+                lines,
+                ..
+            }) => {
+                if let Some(reply_tx) = pending.lock().unwrap().pop_front() {
+                    let _ = reply_tx.send(if success { Ok(lines) } else { Err(lines) });
+                }
+            }
+
+            Some(ControlEvent::Reply { .. }) => (),
+
+            Some(ControlEvent::Notification(notification)) => {
+                handle_notification(&reader_state, &name, &reader_handle, notification)
+            }
+
+            None => (),
         }
         future::ok(())
     });
 
-    handle.await
+    task::spawn(initialize(handle.clone()));
+    task::spawn(refresh_timer(state.clone(), socket.name.to_owned(), handle));
+
+    // the writer only stops once every handle is gone, so it is the reader running into the end
+    // of tmux's output that ends the connection
+    pin_mut!(writer, reader);
+    let result = match future::select(writer, reader).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result,
+    };
+    state.lock().unwrap().remove_tmux_server(&socket.name);
+    result?;
+
+    let status = tmux.status().await?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("tmux exited with {}", status)))
+    }
+}
+
+async fn initialize(handle: TmuxHandle) {
+    // %output notifications are of no use to us, older tmux versions don't know this flag
+    if let Err(err) = handle.command("refresh-client -f no-output").await {
+        debug!("Could not disable tmux output: {}", err);
+    }
+}
+
+fn handle_notification(
+    state: &GlobalState,
+    name: &str,
+    handle: &TmuxHandle,
+    notification: Notification,
+) {
+    match notification {
+        Notification::Other(_) => (),
+        Notification::Exit { reason } => {
+            info!("tmux {}: exit {}", name, reason.unwrap_or_default());
+        }
+        notification => {
+            debug!("tmux {}: {:?}", name, notification);
+            let stale = state
+                .lock()
+                .unwrap()
+                .apply_tmux_notification(name, &notification);
+
+            // a new session always needs a fresh listing, as it comes with windows of its own
+            if stale || matches!(notification, Notification::SessionChanged { .. }) {
+                task::spawn(refresh(state.clone(), name.to_owned(), handle.clone()));
+            }
+        }
+    }
+}
+
+/// Periodically re-read the panes of a server, until the control mode client is gone.
+async fn refresh_timer(state: GlobalState, name: String, handle: TmuxHandle) {
+    loop {
+        task::sleep(PANE_REFRESH_INTERVAL).await;
+        if handle.is_closed() {
+            break;
+        }
+        refresh(state.clone(), name.clone(), handle.clone()).await;
+    }
+}
+
+/// Re-read all sessions, windows and panes of a server.
+pub async fn refresh(state: GlobalState, name: String, handle: TmuxHandle) {
+    let cmd = format!("list-panes -a -F '{}'", PANE_FORMAT);
+    match handle.command(&cmd).await {
+        Ok(lines) => {
+            let fresh = TmuxServer::from_listing(&lines);
+            state.lock().unwrap().update_tmux_server(&name, fresh);
+        }
+        Err(err) => error!("Could not list panes of tmux server {}: {}", name, err),
+    }
 }
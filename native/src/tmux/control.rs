@@ -0,0 +1,382 @@
+//! Parsing of tmux control mode output.
+//!
+//! In control mode tmux writes one line per event. The output of every command is wrapped in a
+//! `%begin`/`%end` (or `%error`) pair of guard lines, everything else starting with `%` is a
+//! notification. See the "CONTROL MODE" section of tmux(1).
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    SessionChanged {
+        id: String,
+        name: String,
+    },
+    SessionsChanged,
+    WindowAdd {
+        id: String,
+    },
+    WindowClose {
+        id: String,
+    },
+    WindowRenamed {
+        id: String,
+        name: String,
+    },
+    LayoutChange {
+        id: String,
+        layout: String,
+        panes: Vec<String>,
+    },
+    Exit {
+        reason: Option<String>,
+    },
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlEvent {
+    /// The output of a command. `from_client` is set for replies to commands we sent, as
+    /// opposed to e.g. the implicit `attach` when the client starts.
+    Reply {
+        number: u64,
+        from_client: bool,
+        success: bool,
+        lines: Vec<String>,
+    },
+    Notification(Notification),
+}
+
+struct Block {
+    number: u64,
+    flags: u32,
+    lines: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Parser {
+    block: Option<Block>,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    /// Feed a single line (without the trailing newline) to the parser. Returns an event once a
+    /// notification or a complete reply has been read.
+    pub fn feed(&mut self, line: &str) -> Option<ControlEvent> {
+        if let Some(mut block) = self.block.take() {
+            let (guard, number) = parse_guard(line).unwrap_or(("", 0));
+            return match guard {
+                "%end" | "%error" if number == block.number => Some(ControlEvent::Reply {
+                    number: block.number,
+                    from_client: block.flags & 1 == 1,
+                    success: guard == "%end",
+                    lines: block.lines,
+                }),
+                _ => {
+                    block.lines.push(line.to_owned());
+                    self.block = Some(block);
+                    None
+                }
+            };
+        }
+
+        if line.starts_with("%begin ") {
+            let mut args = line.split(' ').skip(2);
+            let number = args.next().and_then(|num| num.parse().ok())?;
+            let flags = args.next().and_then(|num| num.parse().ok()).unwrap_or(0);
+            self.block = Some(Block {
+                number,
+                flags,
+                lines: vec![],
+            });
+            return None;
+        }
+
+        parse_notification(line).map(ControlEvent::Notification)
+    }
+}
+
+/// Split a `%begin`, `%end` or `%error` line into its keyword and command number.
+fn parse_guard(line: &str) -> Option<(&str, u64)> {
+    let mut parts = line.split(' ');
+    let guard = parts.next()?;
+    let number = parts.nth(1)?.parse().ok()?;
+    Some((guard, number))
+}
+
+fn parse_notification(line: &str) -> Option<Notification> {
+    if !line.starts_with('%') {
+        return None;
+    }
+
+    let (name, args) = match line.find(' ') {
+        Some(pos) => (&line[..pos], &line[pos + 1..]),
+        None => (line, ""),
+    };
+    let (first, rest) = match args.find(' ') {
+        Some(pos) => (&args[..pos], &args[pos + 1..]),
+        None => (args, ""),
+    };
+
+    use Notification::*;
+    let notification = match name {
+        "%session-changed" => SessionChanged {
+            id: first.to_owned(),
+            name: rest.to_owned(),
+        },
+        "%sessions-changed" => SessionsChanged,
+        // the unlinked-* variants are about windows in sessions other than the attached one,
+        // which are just as much part of the model
+        "%window-add" | "%unlinked-window-add" => WindowAdd {
+            id: first.to_owned(),
+        },
+        "%window-close" | "%unlinked-window-close" => WindowClose {
+            id: first.to_owned(),
+        },
+        "%window-renamed" | "%unlinked-window-renamed" => WindowRenamed {
+            id: first.to_owned(),
+            name: rest.to_owned(),
+        },
+        "%layout-change" => {
+            let layout = rest.split(' ').next().unwrap_or("");
+            LayoutChange {
+                id: first.to_owned(),
+                layout: layout.to_owned(),
+                panes: layout_panes(layout),
+            }
+        }
+        "%exit" => Exit {
+            reason: Some(args.to_owned()).filter(|reason| !reason.is_empty()),
+        },
+        _ => Other(line.to_owned()),
+    };
+    Some(notification)
+}
+
+/// Extract the pane ids (as `%N`) from a window layout string such as
+/// `a8c1,160x48,0,0{80x48,0,0,1,79x48,81,0[79x24,81,0,2,79x23,81,25,3]}`.
+///
+/// Every cell is `WxH,X,Y` followed by either `,ID` for a pane, or a `{...}`/`[...]` group of
+/// comma separated child cells.
+pub fn layout_panes(layout: &str) -> Vec<String> {
+    let mut panes = vec![];
+    // skip the checksum
+    if let Some(pos) = layout.find(',') {
+        let mut input = &layout.as_bytes()[pos + 1..];
+        layout_cell(&mut input, &mut panes);
+    }
+    panes
+}
+
+fn layout_cell(input: &mut &[u8], panes: &mut Vec<String>) -> Option<()> {
+    layout_number(input)?;
+    layout_expect(input, b'x')?;
+    layout_number(input)?;
+    for _ in 0..2 {
+        layout_expect(input, b',')?;
+        layout_number(input)?;
+    }
+
+    match input.first()? {
+        b',' => {
+            *input = &input[1..];
+            panes.push(format!("%{}", layout_number(input)?));
+        }
+        b'{' | b'[' => loop {
+            *input = &input[1..];
+            layout_cell(input, panes)?;
+            match input.first()? {
+                b',' => continue,
+                b'}' | b']' => {
+                    *input = &input[1..];
+                    break;
+                }
+                _ => return None,
+            }
+        },
+        _ => return None,
+    }
+    Some(())
+}
+
+fn layout_number(input: &mut &[u8]) -> Option<u32> {
+    let len = input.iter().take_while(|c| c.is_ascii_digit()).count();
+    let number = std::str::from_utf8(&input[..len]).ok()?.parse().ok()?;
+    *input = &input[len..];
+    Some(number)
+}
+
+fn layout_expect(input: &mut &[u8], expected: u8) -> Option<()> {
+    if input.first() == Some(&expected) {
+        *input = &input[1..];
+        Some(())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Recorded from `tmux -C new-session`, sending `list-windows`, `split-window -h`, an unknown
+    /// command, `rename-window editor` and `kill-server`.
+    const TRANSCRIPT: &str = "\
+%begin 1792311573 259 0
+%end 1792311573 259 0
+%window-add @0
+%sessions-changed
+%session-changed $0 0
+%begin 1792311574 265 1
+@0 bash
+%end 1792311574 265 1
+%begin 1792311574 266 1
+%end 1792311574 266 1
+%window-pane-changed @0 %1
+%layout-change @0 8b5f,160x48,0,0{80x48,0,0,0,79x48,81,0,1} 8b5f,160x48,0,0{80x48,0,0,0,79x48,81,0,1} *
+%begin 1792311574 269 1
+parse error: unknown command: bogus-command
+%error 1792311574 269 1
+%begin 1792311574 270 1
+%end 1792311574 270 1
+%window-renamed @0 editor
+%begin 1792311575 272 1
+%end 1792311575 272 1
+%sessions-changed
+%exit";
+
+    fn parse(transcript: &str) -> Vec<ControlEvent> {
+        let mut parser = Parser::new();
+        transcript
+            .lines()
+            .filter_map(|line| parser.feed(line))
+            .collect()
+    }
+
+    fn reply(number: u64, from_client: bool, success: bool, lines: &[&str]) -> ControlEvent {
+        ControlEvent::Reply {
+            number,
+            from_client,
+            success,
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn transcript() {
+        use Notification::*;
+        let note = ControlEvent::Notification;
+        assert_eq!(
+            parse(TRANSCRIPT),
+            vec![
+                reply(259, false, true, &[]),
+                note(WindowAdd { id: "@0".into() }),
+                note(SessionsChanged),
+                note(SessionChanged {
+                    id: "$0".into(),
+                    name: "0".into()
+                }),
+                reply(265, true, true, &["@0 bash"]),
+                reply(266, true, true, &[]),
+                note(Other("%window-pane-changed @0 %1".into())),
+                note(LayoutChange {
+                    id: "@0".into(),
+                    layout: "8b5f,160x48,0,0{80x48,0,0,0,79x48,81,0,1}".into(),
+                    panes: vec!["%0".into(), "%1".into()],
+                }),
+                reply(
+                    269,
+                    true,
+                    false,
+                    &["parse error: unknown command: bogus-command"]
+                ),
+                reply(270, true, true, &[]),
+                note(WindowRenamed {
+                    id: "@0".into(),
+                    name: "editor".into()
+                }),
+                reply(272, true, true, &[]),
+                note(SessionsChanged),
+                note(Exit { reason: None }),
+            ]
+        );
+    }
+
+    #[test]
+    fn output_that_looks_like_notifications() {
+        // pane ids start with `%` too, but inside a block they are output
+        let transcript = "\
+%begin 1792311580 300 1
+%0
+%1
+%end 1792311580 300 1
+%window-close @1";
+        assert_eq!(
+            parse(transcript),
+            vec![
+                reply(300, true, true, &["%0", "%1"]),
+                ControlEvent::Notification(Notification::WindowClose { id: "@1".into() }),
+            ]
+        );
+    }
+
+    #[test]
+    fn guards_of_other_commands_are_output() {
+        let transcript = "\
+%begin 1792311580 301 1
+%end 1792311580 999 1
+%end 1792311580 301 1";
+        assert_eq!(
+            parse(transcript),
+            vec![reply(301, true, true, &["%end 1792311580 999 1"])]
+        );
+    }
+
+    #[test]
+    fn exit_reason() {
+        assert_eq!(
+            parse_notification("%exit server exited"),
+            Some(Notification::Exit {
+                reason: Some("server exited".into())
+            })
+        );
+        assert_eq!(parse_notification("not a notification"), None);
+    }
+
+    #[test]
+    fn nested_layout() {
+        assert_eq!(
+            layout_panes("a8c1,160x48,0,0{80x48,0,0,1,79x48,81,0[79x24,81,0,2,79x23,81,25,3]}"),
+            vec!["%1", "%2", "%3"]
+        );
+        assert_eq!(layout_panes("b25d,80x24,0,0,7"), vec!["%7"]);
+    }
+
+    #[test]
+    fn malformed_layout() {
+        // panes up to the point where parsing failed are kept
+        assert_eq!(
+            layout_panes("a8c1,160x48,0,0{80x48,0,0,1,oops}"),
+            vec!["%1"]
+        );
+        assert!(layout_panes("").is_empty());
+    }
+
+    #[test]
+    fn unlinked_windows() {
+        use Notification::*;
+        let note = ControlEvent::Notification;
+        assert_eq!(
+            parse("%unlinked-window-add @3\n%unlinked-window-renamed @3 logs\n%unlinked-window-close @3"),
+            vec![
+                note(WindowAdd { id: "@3".into() }),
+                note(WindowRenamed {
+                    id: "@3".into(),
+                    name: "logs".into()
+                }),
+                note(WindowClose { id: "@3".into() }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use crate::tmux::control::Notification;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Format of the `list-panes` listing the model is built from. Fields are tab separated, in the
+/// order `TmuxServer::from_listing` expects them.
+pub const PANE_FORMAT: &str = "#{session_id}\t#{session_name}\t#{window_id}\t#{window_index}\t#{window_name}\t#{window_layout}\t#{pane_id}\t#{pane_index}\t#{pane_current_command}\t#{pane_current_path}\t#{pane_tty}\t#{pane_pid}";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TmuxSession {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TmuxWindow {
+    pub id: String,
+    pub session_id: String,
+    pub index: usize,
+    pub name: String,
+    pub layout: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TmuxPane {
+    pub id: String,
+    pub window_id: String,
+    pub index: usize,
+    pub current_command: String,
+    pub current_path: String,
+    pub tty: String,
+    pub pid: u32,
+}
+
+/// A pane as listed next to sway windows and browser tabs.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct TmuxClient {
+    pub server: String,
+    pub session: String,
+    pub window_index: usize,
+    pub window_name: String,
+    pub pane_id: String,
+    pub current_command: String,
+    pub current_path: String,
+}
+
+/// Sessions, windows and panes of a tmux server, as seen through a control mode client.
+#[derive(Debug, Clone, Default)]
+pub struct TmuxServer {
+    pub name: String,
+    pub current_session: Option<String>,
+    pub sessions: HashMap<String, TmuxSession>,
+    pub windows: HashMap<String, TmuxWindow>,
+    pub panes: HashMap<String, TmuxPane>,
+}
+
+impl TmuxServer {
+    pub fn new(name: &str) -> TmuxServer {
+        TmuxServer {
+            name: name.to_owned(),
+            ..TmuxServer::default()
+        }
+    }
+
+    /// Build the model from the output of `list-panes -a -F PANE_FORMAT`. Lines that don't fit
+    /// the format are skipped.
+    pub fn from_listing(lines: &[String]) -> TmuxServer {
+        let mut server = TmuxServer::default();
+
+        for line in lines {
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            if fields.len() != 12 {
+                continue;
+            }
+
+            server.sessions.insert(
+                fields[0].to_owned(),
+                TmuxSession {
+                    id: fields[0].to_owned(),
+                    name: fields[1].to_owned(),
+                },
+            );
+            server.windows.insert(
+                fields[2].to_owned(),
+                TmuxWindow {
+                    id: fields[2].to_owned(),
+                    session_id: fields[0].to_owned(),
+                    index: fields[3].parse().unwrap_or(0),
+                    name: fields[4].to_owned(),
+                    layout: fields[5].to_owned(),
+                },
+            );
+            server.panes.insert(
+                fields[6].to_owned(),
+                TmuxPane {
+                    id: fields[6].to_owned(),
+                    window_id: fields[2].to_owned(),
+                    index: fields[7].parse().unwrap_or(0),
+                    current_command: fields[8].to_owned(),
+                    current_path: fields[9].to_owned(),
+                    tty: fields[10].to_owned(),
+                    pid: fields[11].parse().unwrap_or(0),
+                },
+            );
+        }
+
+        server
+    }
+
+    /// Replace everything but the name and current session with a fresh listing.
+    pub fn update(&mut self, fresh: TmuxServer) {
+        self.sessions = fresh.sessions;
+        self.windows = fresh.windows;
+        self.panes = fresh.panes;
+    }
+
+    /// Fold a notification into the model. Returns `true` if the notification referred to
+    /// something we don't know enough about, and the model should be re-read from tmux.
+    pub fn apply(&mut self, notification: &Notification) -> bool {
+        use Notification::*;
+        match notification {
+            SessionChanged { id, name } => {
+                self.current_session = Some(id.to_owned());
+                self.sessions.insert(
+                    id.to_owned(),
+                    TmuxSession {
+                        id: id.to_owned(),
+                        name: name.to_owned(),
+                    },
+                );
+                false
+            }
+
+            SessionsChanged => true,
+
+            WindowAdd { id } => !self.windows.contains_key(id),
+
+            WindowClose { id } => {
+                self.windows.remove(id);
+                self.panes.retain(|_, pane| &pane.window_id != id);
+                false
+            }
+
+            WindowRenamed { id, name } => match self.windows.get_mut(id) {
+                Some(window) => {
+                    window.name = name.to_owned();
+                    false
+                }
+                None => true,
+            },
+
+            LayoutChange { id, layout, panes } => {
+                let window = match self.windows.get_mut(id) {
+                    Some(window) => window,
+                    None => return true,
+                };
+                window.layout = layout.to_owned();
+
+                self.panes
+                    .retain(|pane_id, pane| &pane.window_id != id || panes.contains(pane_id));
+                panes
+                    .iter()
+                    .any(|pane_id| !self.panes.contains_key(pane_id))
+            }
+
+            Exit { .. } | Other(_) => false,
+        }
+    }
+
+    /// All panes, ordered by session name, window index and pane index.
+    pub fn clients(&self) -> Vec<TmuxClient> {
+        let mut panes = self
+            .panes
+            .values()
+            .filter_map(|pane| {
+                let window = self.windows.get(&pane.window_id)?;
+                let session = self.sessions.get(&window.session_id)?;
+                Some((session, window, pane))
+            })
+            .collect::<Vec<_>>();
+
+        panes.sort_by(|(s1, w1, p1), (s2, w2, p2)| {
+            (&s1.name, w1.index, p1.index).cmp(&(&s2.name, w2.index, p2.index))
+        });
+
+        panes
+            .into_iter()
+            .map(|(session, window, pane)| TmuxClient {
+                server: self.name.to_owned(),
+                session: session.name.to_owned(),
+                window_index: window.index,
+                window_name: window.name.to_owned(),
+                pane_id: pane.id.to_owned(),
+                current_command: pane.current_command.to_owned(),
+                current_path: pane.current_path.to_owned(),
+            })
+            .collect()
+    }
+
+    pub fn find_pane(&self, pane_id: &str) -> Option<&TmuxPane> {
+        self.panes.get(pane_id)
+    }
+}
@@ -1,15 +1,18 @@
+use crate::auth::{self, PAIRING_WINDOW};
 use crate::browser::*;
 use crate::error::*;
 use crate::message::*;
-use crate::state::{GlobalState, Tx};
+use crate::state::{ClientOrder, GlobalState, PeerAddr, Requester, State, Tx};
 use anyhow::Result;
-use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use async_std::net::{TcpListener, TcpStream};
 use async_std::task;
+use async_tungstenite::tungstenite::handshake::server::{Request, Response};
 use async_tungstenite::tungstenite::protocol::Message;
 use futures::prelude::*;
 use futures::{channel::mpsc::unbounded, channel::mpsc::UnboundedSender, future, pin_mut};
-use log::{error, info};
+use log::{error, info, warn};
 use notify_rust::Notification;
+use serde::Serialize;
 use std::env;
 use std::time::Duration;
 
@@ -44,18 +47,30 @@ async fn accept_connection(
     sway_tx: Tx,
     stream: TcpStream,
 ) -> Result<(), DesktopdError> {
-    let addr = stream
-        .peer_addr()
-        .map_err(|err| DesktopdError::IoError(err))?;
-
-    let ws_stream = async_tungstenite::accept_async(stream)
+    let addr = PeerAddr::Tcp(
+        stream
+            .peer_addr()
+            .map_err(|err| DesktopdError::IoError(err))?,
+    );
+
+    // only a browser extension may pair, so remember where the page that connected came from
+    let mut origin = None;
+    let remember_origin = |req: &Request, res: Response| {
+        origin = req
+            .headers()
+            .get("Origin")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned());
+        Ok(res)
+    };
+    let ws_stream = async_tungstenite::accept_hdr_async(stream, remember_origin)
         .await
         .map_err(|err| DesktopdError::WebSocketError(err))?;
 
     info!("New WebSocket connection: {}", addr);
 
     let (tx, rx) = unbounded();
-    let (write, mut read) = ws_stream.split();
+    let (mut write, mut read) = ws_stream.split();
 
     info!("Waiting for init message from {}", addr);
 
@@ -87,7 +102,14 @@ async fn accept_connection(
         .unwrap_or(Err(DesktopdError::ConnectInitError))?;
 
     let init_state = state.clone();
-    handle_init_message(init_state, &addr, tx, init)?;
+    if let Err(err) = handle_init_message(init_state, &addr, origin.as_deref(), tx, init) {
+        if let Some(msg) = rejection(&err) {
+            let json = serde_json::to_string(&msg)?;
+            write.send(Message::Text(json)).await?;
+            write.close().await?;
+        }
+        return Err(err);
+    }
 
     let answer_channel = rx
         .map(|msg| serde_json::to_string(&msg).unwrap())
@@ -99,7 +121,7 @@ async fn accept_connection(
         .try_for_each(|msg| {
             let sway_handle = sway_tx.clone();
             let receive_state = state.clone();
-            match handle_message(receive_state, sway_handle, msg) {
+            match handle_message(receive_state, sway_handle, &addr, msg) {
                 Ok(_) => future::ok(()),
                 Err(err) => future::err(async_tungstenite::tungstenite::Error::from(
                     std::io::Error::new(
@@ -123,7 +145,7 @@ async fn accept_connection(
     return Ok(());
 }
 
-fn show_notification(message: &str) {
+pub(crate) fn show_notification(message: &str) {
     Notification::new()
         .summary("desktopd")
         .body(message)
@@ -131,7 +153,12 @@ fn show_notification(message: &str) {
         .expect("Could not show notification");
 }
 
-fn handle_message(state: GlobalState, sway_tx: Tx, msg: Message) -> Result<(), DesktopdError> {
+fn handle_message(
+    state: GlobalState,
+    sway_tx: Tx,
+    addr: &PeerAddr,
+    msg: Message,
+) -> Result<(), DesktopdError> {
     let raw = msg
         .to_text()
         .map_err(|err| DesktopdError::WebSocketError(err))?;
@@ -139,38 +166,136 @@ fn handle_message(state: GlobalState, sway_tx: Tx, msg: Message) -> Result<(), D
         .map_err(|err| DesktopdError::SerializationError(err))?;
     let inner_state = state.clone();
     let sway_handle = sway_tx.clone();
-    handle_desktopd_message(inner_state, sway_handle, msg)
+    handle_desktopd_message(inner_state, sway_handle, addr, msg)
 }
 
-fn handle_desktopd_message(
+pub(crate) fn handle_desktopd_message(
     state: GlobalState,
     sway_tx: Tx,
+    addr: &PeerAddr,
     msg: DesktopdMessage,
 ) -> Result<(), DesktopdError> {
     use DesktopdMessage::*;
     match msg {
-        CliRequest(data) => handle_cli_request(state, sway_tx, data),
-        BrowserMessage { data } => handle_browser_response(state, sway_tx, data),
+        CliRequest {
+            request,
+            request_id,
+            ..
+        } => handle_cli_request(state, sway_tx, addr, request, request_id),
+        BrowserMessage { data } => handle_browser_response(state, sway_tx, addr, data),
+        Response {
+            request_id: Some(request_id),
+            result,
+        } => handle_browser_reply(state, addr, request_id, Ok(result)),
+        Error {
+            request_id: Some(request_id),
+            kind,
+            message,
+        } => handle_browser_reply(state, addr, request_id, Err((kind, message))),
+        Subscribe { topics } => {
+            info!("{} subscribed to {:?}", addr, topics);
+            state.lock().unwrap().subscribe(*addr, topics);
+            Ok(())
+        }
         _ => Ok(()),
     }
 }
 
-fn handle_init_message(
+pub(crate) fn handle_init_message(
     state: GlobalState,
-    addr: &SocketAddr,
+    addr: &PeerAddr,
+    origin: Option<&str>,
     tx: UnboundedSender<DesktopdMessage>,
     msg: DesktopdMessage,
 ) -> Result<(), DesktopdError> {
     use DesktopdMessage::*;
     match msg {
-        Connect(conn_type) => handle_connect(state, addr, tx, conn_type),
+        Connect {
+            conn,
+            token,
+            version,
+            capabilities,
+        } => {
+            check_version(version).map_err(|message| {
+                warn!("Rejected connection from {}: {}", addr, message);
+                DesktopdError::IncompatibleVersion(message)
+            })?;
+            let paired = authorize(&state, addr, origin, &conn, token.as_deref())?;
+            // queued before anything handle_connect sends, so it is the first thing the peer sees
+            tx.unbounded_send(DesktopdMessage::welcome())?;
+            state.lock().unwrap().set_capabilities(*addr, capabilities);
+            handle_connect(state.clone(), addr, tx, conn)?;
+            if paired {
+                let state = state.lock().unwrap();
+                let token = state.token().to_owned();
+                send_to_peer(&state, addr, DesktopdMessage::Paired { token })?;
+            }
+            Ok(())
+        }
         _ => Err(DesktopdError::ConnectInitError),
     }
 }
 
+/// What to tell a peer whose `Connect` was refused, before hanging up on it.
+pub(crate) fn rejection(err: &DesktopdError) -> Option<DesktopdMessage> {
+    match err {
+        DesktopdError::IncompatibleVersion(message) => Some(DesktopdMessage::Error {
+            request_id: None,
+            kind: ErrorKind::IncompatibleVersion,
+            message: message.to_owned(),
+        }),
+        DesktopdError::AuthError => Some(DesktopdMessage::Error {
+            request_id: None,
+            kind: ErrorKind::Unauthorized,
+            message: "Invalid or missing token, run `dsktpd pair` to pair again".to_owned(),
+        }),
+        _ => None,
+    }
+}
+
+/// Check the token a peer connected with. Peers on the unix socket need none, as only we can
+/// connect to it. A browser extension without a valid token is let in if pairing is open, in
+/// which case `true` is returned and it should be sent the token. Web pages can claim to be a
+/// browser too, so the `Origin` of the connection has to be an extension's.
+fn authorize(
+    state: &GlobalState,
+    addr: &PeerAddr,
+    origin: Option<&str>,
+    conn: &ConnectionType,
+    token: Option<&str>,
+) -> Result<bool, DesktopdError> {
+    let mut state = state.lock().unwrap();
+    if let PeerAddr::Unix(_) = addr {
+        return Ok(false);
+    }
+    if state.authenticate(token) {
+        return Ok(false);
+    }
+    match origin {
+        Some(origin)
+            if conn.is_browser() && auth::is_extension_origin(origin) && state.take_pairing() =>
+        {
+            info!("Pairing browser extension {} at {}", origin, addr);
+            show_notification(&format!("Browser plugin paired: {} at {}", origin, addr));
+            return Ok(true);
+        }
+        _ if conn.is_browser() && state.pairing_open() => warn!(
+            "Refused to pair {} from origin {}, it is not a browser extension",
+            addr,
+            origin.unwrap_or("unknown")
+        ),
+        _ => (),
+    }
+    warn!(
+        "Rejected connection from {}: invalid or missing token",
+        addr
+    );
+    Err(DesktopdError::AuthError)
+}
+
 fn handle_connect(
     state: GlobalState,
-    addr: &SocketAddr,
+    addr: &PeerAddr,
     tx: UnboundedSender<DesktopdMessage>,
     tipe: ConnectionType,
 ) -> Result<(), DesktopdError> {
@@ -191,12 +316,12 @@ fn handle_connect(
         Cli => {
             info!("Accepted client connection from {}", addr);
             state.add_peer(tipe, *addr, tx);
-            let clients = state.clients();
+            let clients = state.clients(ClientOrder::Frecency);
             let init = DesktopdMessage::ClientList { data: clients };
             let peer: Tx = state
                 .find_peer(&addr)
                 .map(|handle| Ok(handle.clone()))
-                .unwrap_or(Err(DesktopdError::ConnectInitError))?;
+                .unwrap_or(Err(DesktopdError::PeerGone(*addr)))?;
             peer.unbounded_send(init)
                 .map_err(|err| DesktopdError::ChannelError(err))
         }
@@ -206,6 +331,7 @@ fn handle_connect(
 fn handle_browser_response(
     state: GlobalState,
     sway_tx: Tx,
+    addr: &PeerAddr,
     data: BrowserResponse,
 ) -> Result<(), DesktopdError> {
     let mut state = state.lock().unwrap();
@@ -213,60 +339,391 @@ fn handle_browser_response(
     match data {
         Init { data: tabs } => {
             info!("Received initial tab list from browser");
-            for tab in tabs {
-                state.add_tab(tab)
-            }
+            state.batch(|state| {
+                for tab in tabs {
+                    state.set_browser_owner(tab.window_id, *addr);
+                    state.add_tab(tab)
+                }
+            });
+            tag_browser_windows(&mut state);
+            Ok(())
+        }
+
+        Created { data: tab } => {
+            info!("Created tab {}", tab.id);
+            state.set_browser_owner(tab.window_id, *addr);
+            state.add_tab(tab);
+            tag_browser_windows(&mut state);
             Ok(())
         }
 
         Updated { data: tab } => {
             info!("Updated tab {}", tab.id);
+            state.set_browser_owner(tab.window_id, *addr);
             state.add_tab(tab);
             Ok(())
         }
 
         Removed(tab) => {
             info!("Removed tab {}", tab.tab_id);
+            state.forget_client(&ClientRef::Tab(tab.clone()));
             state.remove_tab(tab);
             Ok(())
         }
 
-        Activated(_) => sway_tx
-            .unbounded_send(DesktopdMessage::BrowserMessage { data })
-            .map_err(|err| DesktopdError::ChannelError(err)),
+        Attached {
+            tab_id,
+            new_window_id,
+            new_position,
+        } => {
+            info!("Attached tab {} to window {}", tab_id, new_window_id);
+            state.set_browser_owner(new_window_id, *addr);
+            state.attach_tab(tab_id, new_window_id, new_position);
+            tag_browser_windows(&mut state);
+            Ok(())
+        }
+
+        Detached {
+            tab_id,
+            old_window_id,
+            ..
+        } => {
+            info!("Detached tab {} from window {}", tab_id, old_window_id);
+            state.detach_tab(tab_id, old_window_id);
+            Ok(())
+        }
+
+        Moved {
+            tab_id,
+            window_id,
+            to_index,
+            ..
+        } => {
+            info!("Moved tab {} to index {}", tab_id, to_index);
+            state.move_tab(tab_id, window_id, to_index);
+            Ok(())
+        }
+
+        Replaced {
+            added_tab_id,
+            removed_tab_id,
+        } => {
+            info!("Replaced tab {} with {}", removed_tab_id, added_tab_id);
+            state.replace_tab(added_tab_id, removed_tab_id);
+            Ok(())
+        }
+
+        Activated(ref tab) => {
+            state.activate_tab(tab);
+            state.record_focus(ClientRef::Tab(tab.clone()));
+            sway_tx
+                .unbounded_send(DesktopdMessage::BrowserMessage { data })
+                .map_err(|err| DesktopdError::ChannelError(err))
+        }
 
         _ => Ok(()),
     }
 }
 
-fn handle_cli_request(
+/// Ask the browser to mark all windows we cannot yet map to a sway container.
+pub(crate) fn tag_browser_windows(state: &mut State) {
+    for (window_id, nonce) in state.tag_browser_windows() {
+        let req = BrowserRequest::TagWindow { window_id, nonce };
+        for (peer_addr, peer) in state.get_browser_connections() {
+            let msg = DesktopdMessage::BrowserRequest {
+                request: req.clone(),
+                request_id: None,
+            };
+            match peer.unbounded_send(msg) {
+                Ok(_) => info!("Sent tag-window message for window {}", window_id),
+                Err(e) => error!("Could not send message to browser {}: {}", peer_addr, e),
+            }
+        }
+    }
+}
+
+pub(crate) fn handle_cli_request(
     state: GlobalState,
     sway_tx: Tx,
+    addr: &PeerAddr,
     data: CliRequest,
+    request_id: Option<u64>,
 ) -> Result<(), DesktopdError> {
     let mut state = state.lock().unwrap();
+    let requester = Requester {
+        addr: *addr,
+        request_id,
+    };
+    // sway does the actual work for most requests, and answers them when it's done
+    let forward = |request| {
+        sway_tx.unbounded_send(DesktopdMessage::CliRequest {
+            request,
+            request_id,
+            origin: Some(*addr),
+        })
+    };
 
     use CliRequest::*;
     match &data {
-        FocusWindow { .. } => sway_tx
-            .unbounded_send(DesktopdMessage::CliRequest(data))
-            .map_err(|err| DesktopdError::ChannelError(err)),
-
-        FocusTab { .. } => {
-            for (peer_addr, peer) in state.get_browser_connections() {
-                match peer.unbounded_send(DesktopdMessage::CliRequest(data.clone())) {
-                    Ok(_) => info!("Successfully sent focus-tab message to browsers"),
-                    Err(e) => {
-                        if let Some((conn, _)) = state.remove_peer(&peer_addr) {
-                            if conn.is_browser() {
-                                show_notification("Browser Plugin disconnected")
-                            }
-                        }
-                        error!("Could not send message to browser {}: {}", peer_addr, e)
+        FocusWindow { .. } | FocusTmuxPane { .. } | Resync => forward(data)?,
+
+        CloseWindow { id }
+        | MoveWindowToWorkspace { id, .. }
+        | MoveWindowToOutput { id, .. }
+        | ToggleFloating { id }
+        | ToggleFullscreen { id }
+        | SendToScratchpad { id } => {
+            if state.window(id).is_some() {
+                forward(data)?
+            } else {
+                let message = format!("No window {}", id);
+                state.reply(&requester, Err((ErrorKind::NotFound, message)));
+            }
+        }
+
+        FocusTab(tab) => focus_tab(&mut state, &sway_tx, requester, tab)?,
+
+        CloseTab(_)
+        | ReloadTab(_)
+        | DuplicateTab(_)
+        | PinTab { .. }
+        | MuteTab { .. }
+        | MoveTabToWindow { .. }
+        | OpenUrl { .. } => tab_command(&mut state, requester, data)?,
+
+        FocusPrevious | FocusNext => {
+            let target = match data {
+                FocusPrevious => state.previous_client(),
+                _ => state.next_client(),
+            };
+            match target {
+                Some(ClientRef::Window { id }) => forward(FocusWindow { id })?,
+                Some(ClientRef::Tab(tab)) => focus_tab(&mut state, &sway_tx, requester, &tab)?,
+                None => {
+                    info!("No client to switch to");
+                    let message = "No client to switch to".to_owned();
+                    state.reply(&requester, Err((ErrorKind::NotFound, message)));
+                }
+            }
+        }
+
+        ListWorkspaces => {
+            let data = state.workspaces().clone();
+            answer_query(&state, &requester, data, |data| {
+                DesktopdMessage::WorkspaceList { data }
+            })?
+        }
+
+        ListOutputs => {
+            let data = state.outputs().clone();
+            answer_query(&state, &requester, data, |data| {
+                DesktopdMessage::OutputList { data }
+            })?
+        }
+
+        LastBinding => {
+            let data = state.last_binding().cloned();
+            answer_query(&state, &requester, data, |data| DesktopdMessage::Binding {
+                data,
+            })?
+        }
+
+        PairBrowser => {
+            info!("Pairing open for {:?}", PAIRING_WINDOW);
+            state.open_pairing(PAIRING_WINDOW);
+            state.reply(&requester, Ok(serde_json::Value::Null));
+        }
+    }
+    Ok(())
+}
+
+/// Queries are answered with their own message, unless the peer asked for a `Response` by
+/// giving a request id.
+fn answer_query<T: Serialize>(
+    state: &State,
+    requester: &Requester,
+    data: T,
+    msg: fn(T) -> DesktopdMessage,
+) -> Result<(), DesktopdError> {
+    match requester.request_id {
+        Some(_) => state.reply(requester, Ok(serde_json::to_value(data)?)),
+        None => send_to_peer(state, &requester.addr, msg(data))?,
+    }
+    Ok(())
+}
+
+fn focus_tab(
+    state: &mut State,
+    sway_tx: &Tx,
+    requester: Requester,
+    tab: &BrowserTabRef,
+) -> Result<(), DesktopdError> {
+    let browsers = state.get_browser_connections();
+    if browsers.is_empty() {
+        let message = "No browser is connected".to_owned();
+        state.reply(&requester, Err((ErrorKind::NotConnected, message)));
+        return Ok(());
+    }
+
+    // the tab might already be active, in which case the browser won't tell us, so focus its
+    // window right away if we know it and leave answering the request to sway, or answer it
+    // ourselves if we don't
+    let active = state.find_tab(tab).map(|tab| tab.active).unwrap_or(false);
+    match state.find_browser_container(&tab.window_id) {
+        Some(id) if active => sway_tx.unbounded_send(DesktopdMessage::CliRequest {
+            request: CliRequest::FocusWindow { id },
+            request_id: requester.request_id,
+            origin: Some(requester.addr),
+        })?,
+        Some(id) => {
+            state.request_focus(tab.tab_id, requester);
+            sway_tx.unbounded_send(DesktopdMessage::cli_request(CliRequest::FocusWindow { id }))?
+        }
+        None if active => {
+            let message = format!(
+                "Tab {} is already active, but the sway window of browser window {} is not known yet",
+                tab.tab_id, tab.window_id
+            );
+            state.reply(&requester, Err((ErrorKind::FocusFailed, message)));
+        }
+        None => state.request_focus(tab.tab_id, requester),
+    }
+
+    for (peer_addr, peer) in browsers {
+        let req = DesktopdMessage::cli_request(CliRequest::FocusTab(tab.clone()));
+        match peer.unbounded_send(req) {
+            Ok(_) => info!("Successfully sent focus-tab message to browsers"),
+            Err(e) => {
+                if let Some((conn, _)) = state.remove_peer(&peer_addr) {
+                    if conn.is_browser() {
+                        show_notification("Browser Plugin disconnected")
                     }
                 }
+                error!("Could not send message to browser {}: {}", peer_addr, e)
             }
-            Ok(())
         }
     }
+    Ok(())
+}
+
+/// Pass a tab command on to the browser it concerns. It is answered once the browser
+/// acknowledges it, see `handle_browser_reply`.
+fn tab_command(
+    state: &mut State,
+    requester: Requester,
+    data: CliRequest,
+) -> Result<(), DesktopdError> {
+    use CliRequest::*;
+    let request = match data {
+        CloseTab(tab) => BrowserRequest::CloseTab(tab),
+        ReloadTab(tab) => BrowserRequest::ReloadTab(tab),
+        DuplicateTab(tab) => BrowserRequest::DuplicateTab(tab),
+        PinTab { tab, pinned } => BrowserRequest::PinTab { tab, pinned },
+        MuteTab { tab, muted } => BrowserRequest::MuteTab { tab, muted },
+        MoveTabToWindow {
+            tab,
+            to_window_id,
+            index,
+        } => BrowserRequest::MoveTabToWindow {
+            tab,
+            to_window_id,
+            index,
+        },
+        OpenUrl { url, window } => BrowserRequest::OpenUrl {
+            url,
+            window_id: window,
+        },
+        _ => return Ok(()),
+    };
+
+    match browser_for(state, &request) {
+        Ok(browser) if state.has_capability(&browser, TAB_COMMANDS) => {
+            let request_id = Some(state.request_browser(browser, requester));
+            let msg = DesktopdMessage::BrowserRequest {
+                request,
+                request_id,
+            };
+            send_to_peer(state, &browser, msg)?
+        }
+        Ok(browser) => {
+            warn!("{} does not support tab commands", browser);
+            let message = "The browser extension is too old for tab commands".to_owned();
+            state.reply(&requester, Err((ErrorKind::NotConnected, message)));
+        }
+        Err(err) => state.reply(&requester, Err(err)),
+    }
+    Ok(())
+}
+
+/// The browser a request has to go to: the one the tab or window belongs to, or for a url
+/// without a window, the one last used.
+fn browser_for(state: &State, request: &BrowserRequest) -> Result<PeerAddr, (ErrorKind, String)> {
+    use BrowserRequest::*;
+    let tab = match request {
+        OpenUrl {
+            window_id: None, ..
+        } => {
+            let message = "No browser is connected".to_owned();
+            return state
+                .last_browser()
+                .ok_or((ErrorKind::NotConnected, message));
+        }
+        OpenUrl {
+            window_id: Some(window_id),
+            ..
+        }
+        | TagWindow { window_id, .. } => return window_browser(state, window_id),
+        FocusTab(tab) | CloseTab(tab) | ReloadTab(tab) | DuplicateTab(tab) => tab,
+        PinTab { tab, .. } | MuteTab { tab, .. } | MoveTabToWindow { tab, .. } => tab,
+    };
+
+    if state.find_tab(tab).is_none() {
+        let message = format!("No tab {} in window {}", tab.tab_id, tab.window_id);
+        return Err((ErrorKind::NotFound, message));
+    }
+    let browser = window_browser(state, &tab.window_id)?;
+    if let MoveTabToWindow { to_window_id, .. } = request {
+        if window_browser(state, to_window_id)? != browser {
+            let message = "Tabs can only be moved between windows of the same browser".to_owned();
+            return Err((ErrorKind::InvalidRequest, message));
+        }
+    }
+    Ok(browser)
+}
+
+fn window_browser(state: &State, window_id: &usize) -> Result<PeerAddr, (ErrorKind, String)> {
+    match state.browser_owner(window_id) {
+        Some(browser) => Ok(browser),
+        None if state.get_browser_connections().is_empty() => Err((
+            ErrorKind::NotConnected,
+            "No browser is connected".to_owned(),
+        )),
+        None => Err((
+            ErrorKind::NotFound,
+            format!("No browser window {}", window_id),
+        )),
+    }
+}
+
+/// A browser acknowledged a request, pass its answer on to whoever asked.
+fn handle_browser_reply(
+    state: GlobalState,
+    addr: &PeerAddr,
+    request_id: u64,
+    result: RequestResult,
+) -> Result<(), DesktopdError> {
+    let mut state = state.lock().unwrap();
+    match state.take_browser_request(addr, request_id) {
+        Some(requester) => state.reply(&requester, result),
+        None => warn!("{} answered unknown request {}", addr, request_id),
+    }
+    Ok(())
+}
+
+fn send_to_peer(state: &State, addr: &PeerAddr, msg: DesktopdMessage) -> Result<(), DesktopdError> {
+    let peer = state
+        .find_peer(addr)
+        .ok_or(DesktopdError::PeerGone(*addr))?;
+    peer.unbounded_send(msg)?;
+    Ok(())
 }